@@ -0,0 +1 @@
+alter table todos add column deleted_at integer;
@@ -0,0 +1,26 @@
+-- Recreate todos with autoincrement so ids of purged todos are never reused, which would
+-- otherwise mix their history with that of a new todo
+create table todos_new (
+    id integer primary key autoincrement not null,
+    description text not null,
+    done boolean not null default 0,
+    deleted_at integer
+);
+insert into todos_new (id, description, done, deleted_at)
+select id, description, done, deleted_at from todos;
+drop table todos;
+alter table todos_new rename to todos;
+
+create table if not exists todo_events (
+    id integer primary key not null,
+    todo_id integer not null,
+    revision integer not null,
+    action text not null,
+    old_description text,
+    old_done boolean,
+    new_description text,
+    new_done boolean,
+    actor text not null,
+    created_at integer not null default (unixepoch()),
+    unique (todo_id, revision)
+);
@@ -0,0 +1,22 @@
+create table if not exists webhooks (
+    id integer primary key autoincrement not null,
+    url text not null,
+    secret text not null,
+    created_at integer not null default (unixepoch())
+);
+
+-- Deliveries are kept until they succeed or run out of attempts, so that they are retried
+-- across restarts. Deliveries that run out of attempts are kept as dead letters.
+create table if not exists webhook_deliveries (
+    id integer primary key autoincrement not null,
+    webhook_id integer not null references webhooks (id) on delete cascade,
+    payload text not null,
+    status text not null default 'pending',
+    attempts integer not null default 0,
+    next_attempt_at integer not null default (unixepoch()),
+    last_error text,
+    created_at integer not null default (unixepoch())
+);
+
+create index if not exists webhook_deliveries_due
+on webhook_deliveries (status, next_attempt_at);
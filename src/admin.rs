@@ -0,0 +1,368 @@
+//! Maintenance of the database, behind the server binary's subcommands: migrating, seeding
+//! with fake data, and backing up and restoring. Backups and restores are consistent
+//! snapshots made with `VACUUM INTO`.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteConnection, SqliteLockingMode},
+    Connection, Pool, Sqlite,
+};
+
+use crate::{
+    endpoints::Todo,
+    provider::{ProviderError, TodoProvider},
+};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Recorded as the actor in the history of seeded todos
+const SEED_ACTOR: &str = "seed";
+/// How long to wait for the database to be unlocked by others that are finishing with it
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+/// The result code for files that are not databases
+const SQLITE_NOTADB: &str = "26";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// Applied, but the migration has been changed since
+    Modified,
+    /// Applied, but not known to this version of the server
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+/// The state of every migration, known or applied, in order of version
+pub async fn migration_status(pool: &Pool<Sqlite>) -> anyhow::Result<Vec<MigrationStatus>> {
+    let mut connection = pool.acquire().await?;
+    let mut applied = applied_migrations(&mut connection).await?;
+
+    let mut statuses: Vec<MigrationStatus> = MIGRATOR
+        .iter()
+        .map(|migration| {
+            let state = match applied.remove(&migration.version) {
+                Some(checksum) if checksum == *migration.checksum => MigrationState::Applied,
+                Some(_) => MigrationState::Modified,
+                None => MigrationState::Pending,
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state,
+            }
+        })
+        .collect();
+    statuses.extend(applied.into_keys().map(|version| MigrationStatus {
+        version,
+        description: String::new(),
+        state: MigrationState::Unknown,
+    }));
+
+    statuses.sort_by_key(|status| status.version);
+    Ok(statuses)
+}
+
+/// Applies pending migrations, or only lists them if `dry_run`, returning those pending
+pub async fn migrate(pool: &Pool<Sqlite>, dry_run: bool) -> anyhow::Result<Vec<MigrationStatus>> {
+    let pending = migration_status(pool)
+        .await?
+        .into_iter()
+        .filter(|status| status.state == MigrationState::Pending)
+        .collect();
+
+    if !dry_run {
+        MIGRATOR.run(pool).await?;
+    }
+    Ok(pending)
+}
+
+/// Adds `count` todos with fake descriptions, some of them done. The same todos are added
+/// every time.
+pub async fn seed(provider: &impl TodoProvider, count: usize) -> Result<Vec<Todo>, ProviderError> {
+    const VERBS: [&str; 8] = [
+        "Buy",
+        "Clean",
+        "Fix",
+        "Call about",
+        "Return",
+        "Book",
+        "Paint",
+        "Sort out",
+    ];
+    const OBJECTS: [&str; 10] = [
+        "the car",
+        "the kitchen",
+        "the bike",
+        "the dentist",
+        "library books",
+        "a haircut",
+        "the fence",
+        "the garage",
+        "birthday presents",
+        "the gutters",
+    ];
+
+    let mut random = Lcg(0x5eed);
+    let mut todos = Vec::with_capacity(count);
+    for _ in 0..count {
+        let verb = VERBS[random.below(VERBS.len())];
+        let object = OBJECTS[random.below(OBJECTS.len())];
+        let done = random.below(4) == 0;
+
+        let description = format!("{verb} {object}");
+        let mut todo = provider.add_todo(&description, SEED_ACTOR).await?;
+        if done {
+            let id = todo.id;
+            todo = provider
+                .update_todo(id, &description, true, SEED_ACTOR)
+                .await?
+                .ok_or_else(|| ProviderError(anyhow::anyhow!("todo {id} was deleted")))?;
+        }
+        todos.push(todo);
+    }
+    Ok(todos)
+}
+
+/// A linear congruential generator, which is plenty for fake data
+struct Lcg(u64);
+
+impl Lcg {
+    fn below(&mut self, n: usize) -> usize {
+        // Knuth's MMIX constants, taking the high bits which are the most random
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % n as u64) as usize
+    }
+}
+
+/// Writes a consistent snapshot of the database to `path`, which must not exist, while it
+/// is in use
+pub async fn backup(pool: &Pool<Sqlite>, path: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(!path.exists(), "{} already exists", path.display());
+
+    sqlx::query("VACUUM INTO ?")
+        .bind(path_str(path)?)
+        .execute(pool)
+        .await
+        .with_context(|| format!("failed to back up to {}", path.display()))?;
+
+    let mut connection = open_read_only(path).await?;
+    check_integrity(&mut connection).await
+}
+
+/// Replaces the database at `database` with the backup at `backup`, which is first checked
+/// for corruption and for migrations this version of the server does not know. The server
+/// must not be running.
+pub async fn restore(backup: &Path, database: &Path) -> anyhow::Result<()> {
+    let mut connection = open_read_only(backup).await?;
+    check_integrity(&mut connection).await?;
+    check_migrations(&mut connection).await?;
+
+    // The database is only replaced once the copy is complete, so that a failed restore
+    // leaves it as it was
+    let copy = with_suffix(database, ".restoring");
+    remove_if_exists(&copy)?;
+    sqlx::query("VACUUM INTO ?")
+        .bind(path_str(&copy)?)
+        .execute(&mut connection)
+        .await
+        .with_context(|| format!("failed to copy {}", backup.display()))?;
+    connection.close().await?;
+
+    // Held until the database is replaced, so that a server cannot be using it meanwhile
+    let lock = match lock(database).await {
+        Ok(lock) => lock,
+        Err(err) => {
+            remove_if_exists(&copy)?;
+            return Err(err);
+        }
+    };
+
+    // Journals left by the old database would be applied to the restored one
+    for suffix in ["-wal", "-shm", "-journal"] {
+        remove_if_exists(&with_suffix(database, suffix))?;
+    }
+    fs::rename(&copy, database)
+        .with_context(|| format!("failed to replace {}", database.display()))?;
+
+    if let Some(lock) = lock {
+        lock.close().await?;
+    }
+    Ok(())
+}
+
+/// Takes an exclusive lock on the database at `path`, failing if it is open elsewhere. There
+/// is nothing to lock if the database does not exist, or is not a database at all.
+async fn lock(path: &Path) -> anyhow::Result<Option<SqliteConnection>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .locking_mode(SqliteLockingMode::Exclusive)
+        .busy_timeout(LOCK_TIMEOUT);
+    let mut connection = SqliteConnection::connect_with(&options)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+
+    // The lock is taken by writing, and held until the connection is closed
+    let result = sqlx::query("BEGIN EXCLUSIVE; COMMIT")
+        .execute(&mut connection)
+        .await;
+    match result {
+        Ok(_) => Ok(Some(connection)),
+        Err(err) => {
+            // Otherwise the connection is closed in the background, perhaps still holding a
+            // lock when the restore is tried again
+            connection.close().await?;
+            match err {
+                sqlx::Error::Database(err) if err.code().as_deref() == Some(SQLITE_NOTADB) => {
+                    Ok(None)
+                }
+                err => Err(err).with_context(|| {
+                    format!("failed to lock {}, which may be in use", path.display())
+                }),
+            }
+        }
+    }
+}
+
+/// The path of the database file in a `sqlite:` URL such as `DATABASE_URL`
+pub fn database_path(url: &str) -> anyhow::Result<PathBuf> {
+    let path = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+        .with_context(|| format!("{url} is not a sqlite: URL"))?;
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    anyhow::ensure!(
+        !path.is_empty() && path != ":memory:",
+        "{url} is not a database file"
+    );
+    Ok(path.into())
+}
+
+async fn open_read_only(path: &Path) -> anyhow::Result<SqliteConnection> {
+    anyhow::ensure!(path.exists(), "{} does not exist", path.display());
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    SqliteConnection::connect_with(&options)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))
+}
+
+async fn check_integrity(connection: &mut SqliteConnection) -> anyhow::Result<()> {
+    let problems: Vec<(String,)> = sqlx::query_as("PRAGMA integrity_check")
+        .fetch_all(&mut *connection)
+        .await?;
+    if problems.len() != 1 || problems[0].0 != "ok" {
+        let problems: Vec<String> = problems.into_iter().map(|(problem,)| problem).collect();
+        anyhow::bail!("the database is corrupt: {}", problems.join("; "));
+    }
+
+    let violations: Vec<(String,)> = sqlx::query_as("PRAGMA foreign_key_check")
+        .fetch_all(&mut *connection)
+        .await?;
+    if let Some((table,)) = violations.first() {
+        anyhow::bail!(
+            "the database has {} broken references, the first in {table}",
+            violations.len()
+        );
+    }
+    Ok(())
+}
+
+/// Checks that the database is of the todo schema, and at a version this server knows
+async fn check_migrations(connection: &mut SqliteConnection) -> anyhow::Result<()> {
+    let applied = applied_migrations(connection).await?;
+    anyhow::ensure!(
+        !applied.is_empty(),
+        "the database has no migrations applied, so is not a todo database"
+    );
+
+    for (version, checksum) in applied {
+        match MIGRATOR
+            .iter()
+            .find(|migration| migration.version == version)
+        {
+            Some(migration) if *migration.checksum == *checksum => {}
+            Some(_) => anyhow::bail!("migration {version} differs from this server's"),
+            None => anyhow::bail!("migration {version} is newer than this server"),
+        }
+    }
+    Ok(())
+}
+
+/// The checksums of applied migrations by version, which are none if the database has
+/// never been migrated
+async fn applied_migrations(
+    connection: &mut SqliteConnection,
+) -> anyhow::Result<HashMap<i64, Vec<u8>>> {
+    let (exists,): (bool,) = sqlx::query_as(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(&mut *connection)
+    .await?;
+    if !exists {
+        return Ok(HashMap::new());
+    }
+
+    let applied: Vec<(i64, Vec<u8>)> =
+        sqlx::query_as("SELECT version, checksum FROM _sqlx_migrations WHERE success")
+            .fetch_all(&mut *connection)
+            .await?;
+    Ok(applied.into_iter().collect())
+}
+
+fn path_str(path: &Path) -> anyhow::Result<&str> {
+    path.to_str()
+        .with_context(|| format!("{} is not valid UTF-8", path.display()))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+fn remove_if_exists(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("failed to remove {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_database_path() {
+        assert_eq!(
+            database_path("sqlite:sqlite.db").unwrap(),
+            PathBuf::from("sqlite.db")
+        );
+        assert_eq!(
+            database_path("sqlite:///var/lib/todos/todos.db?mode=rwc").unwrap(),
+            PathBuf::from("/var/lib/todos/todos.db")
+        );
+        assert!(database_path("sqlite::memory:").is_err());
+        assert!(database_path("postgres://localhost/todos").is_err());
+    }
+}
@@ -1,48 +1,204 @@
+use std::time::Duration;
+
+use async_graphql_axum::GraphQLSubscription;
 use axum::{
-    http::StatusCode,
+    extract::DefaultBodyLimit,
+    http::{header, HeaderValue, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
-    routing::get,
-    Router,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use serde::Serialize;
+use tower_http::{
+    compression::{
+        predicate::{Predicate, SizeAbove},
+        CompressionLayer, DefaultPredicate,
+    },
+    decompression::RequestDecompressionLayer,
 };
 
 use crate::{
-    endpoints,
-    provider::{ProviderError, TodoProvider},
+    body_limit,
+    changes::ChangeFeed,
+    config::AppConfig,
+    endpoints, graphql, grpc,
+    provider::{BackupProvider, ProviderError, TodoProvider, WebhookProvider},
+    rate_limit::{self, RateLimiter},
+    request_id,
+    resilience::Unavailable,
 };
 
 pub trait AppState: Clone + Send + Sync + 'static {
     type P: TodoProvider;
+    type W: WebhookProvider;
+    type B: BackupProvider;
 
     fn provider(&self) -> &Self::P;
+    fn webhooks(&self) -> &Self::W;
+    fn backups(&self) -> &Self::B;
+    /// Where changes made through the endpoints are published
+    fn changes(&self) -> &ChangeFeed;
+    fn rate_limiter(&self) -> &RateLimiter;
+    fn config(&self) -> &AppConfig;
 }
 
 pub fn router<A: AppState>(state: A) -> Router {
-    Router::new()
+    let schema = graphql::schema(state.clone());
+
+    let router = Router::new()
         .route(
             "/todos",
             get(endpoints::get_todos::<A>).post(endpoints::add_todo::<A>),
         )
         .route(
             "/todos/:id",
-            get(endpoints::get_todo::<A>).put(endpoints::update_todo::<A>),
+            get(endpoints::get_todo::<A>)
+                .put(endpoints::update_todo::<A>)
+                .delete(endpoints::delete_todo::<A>),
+        )
+        .route("/todos/batch", post(endpoints::apply_batch::<A>))
+        .route("/todos/events", get(endpoints::todo_events::<A>))
+        .route("/todos/export.csv", get(endpoints::export_csv::<A>))
+        .route("/todos/import", post(endpoints::import_todos::<A>))
+        .route("/todos.ics", get(endpoints::export_ical::<A>))
+        .route("/todos/:id/history", get(endpoints::get_history::<A>))
+        .route(
+            "/todos/:id/revisions/:rev/restore",
+            post(endpoints::restore_revision::<A>),
+        )
+        .route(
+            "/trash",
+            get(endpoints::get_trash::<A>).delete(endpoints::purge_trash::<A>),
+        )
+        .route("/trash/:id/restore", post(endpoints::restore_todo::<A>))
+        .route("/ws", get(endpoints::websocket::<A>))
+        .route(
+            "/webhooks",
+            get(endpoints::get_webhooks::<A>).post(endpoints::add_webhook::<A>),
+        )
+        .route("/webhooks/:id", delete(endpoints::delete_webhook::<A>))
+        .route(
+            "/webhooks/dead-letters",
+            get(endpoints::get_dead_letters::<A>),
+        )
+        .route(
+            "/webhooks/dead-letters/:id/retry",
+            post(endpoints::retry_dead_letter::<A>),
+        )
+        .route("/admin/backup", post(endpoints::backup::<A>))
+        .route(
+            "/graphql",
+            get(endpoints::graphiql).post(endpoints::graphql::<A>),
         )
-        .with_state(state)
+        // gRPC and GraphQL subscriptions stream their bodies, so are left to limit themselves
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            body_limit::limit::<A>,
+        ))
+        // Replaced by the limits above, which may be higher
+        .route_layer(DefaultBodyLimit::disable())
+        .route_service("/graphql/ws", GraphQLSubscription::new(schema.clone()))
+        .route_service(&grpc::route::<A>(), grpc::service(state.clone()))
+        .layer(Extension(schema))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit::<A>,
+        ))
+        // Preflight requests are answered before they count towards the rate limit
+        .layer(state.config().cors.layer())
+        .layer(RequestDecompressionLayer::new())
+        .layer(CompressionLayer::new().compress_when(
+            DefaultPredicate::new().and(SizeAbove::new(state.config().compression.min_size)),
+        ));
+    with_request_ids(router).with_state(state)
+}
+
+/// Serves only gRPC, for its own port. Requests are rate limited and traced as they are by
+/// [`router`].
+pub fn grpc_router<A: AppState>(state: A) -> Router {
+    let router = Router::new()
+        .route_service(&grpc::route::<A>(), grpc::service(state.clone()))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit::<A>,
+        ));
+    with_request_ids(router).with_state(state)
+}
+
+/// Gives requests an ID, and traces them in a span recording it
+fn with_request_ids<A: AppState>(router: Router<A>) -> Router<A> {
+    router
+        .layer(middleware::from_fn(request_id::scope))
+        .layer(request_id::propagate_layer())
+        .layer(request_id::trace_layer())
+        .layer(request_id::set_layer())
 }
 
 pub enum AppError {
+    BadRequest(String),
+    Unauthorized,
     NotFound,
+    UnsupportedMediaType,
+    PayloadTooLarge { limit: usize },
+    TooManyRequests { retry_after: Duration },
+    ServiceUnavailable { retry_after: Duration },
     InternalServerError(anyhow::Error),
 }
 
+/// The body of every error response
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    /// The ID of the request, to be quoted when reporting the error
+    request_id: Option<String>,
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        match self {
-            AppError::NotFound => StatusCode::NOT_FOUND.into_response(),
+        let (status, error, headers) = match self {
+            AppError::BadRequest(message) => (StatusCode::BAD_REQUEST, message, None),
+            AppError::Unauthorized => (
+                StatusCode::UNAUTHORIZED,
+                "unauthorized".to_string(),
+                Some([(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))]),
+            ),
+            AppError::NotFound => (StatusCode::NOT_FOUND, "not found".to_string(), None),
+            AppError::UnsupportedMediaType => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported media type".to_string(),
+                None,
+            ),
+            AppError::PayloadTooLarge { limit } => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("request body is larger than {limit} bytes"),
+                None,
+            ),
+            AppError::TooManyRequests { retry_after } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many requests".to_string(),
+                Some([(header::RETRY_AFTER, rate_limit::seconds(retry_after))]),
+            ),
+            AppError::ServiceUnavailable { retry_after } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "service unavailable".to_string(),
+                Some([(header::RETRY_AFTER, rate_limit::seconds(retry_after))]),
+            ),
             AppError::InternalServerError(err) => {
                 tracing::error!("{}", err);
-                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal error".to_string(),
+                    None,
+                )
             }
-        }
+        };
+
+        let body = ErrorBody {
+            error,
+            request_id: request_id::current(),
+        };
+        (status, headers, Json(body)).into_response()
     }
 }
 
@@ -57,13 +213,18 @@ where
 
 impl From<ProviderError> for AppError {
     fn from(value: ProviderError) -> Self {
-        AppError::InternalServerError(value.0)
+        match value.0.downcast_ref::<Unavailable>() {
+            Some(unavailable) => AppError::ServiceUnavailable {
+                retry_after: unavailable.retry_after,
+            },
+            None => AppError::InternalServerError(value.0),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{sync::Arc, time::Duration};
 
     use axum::{
         body::Body,
@@ -74,29 +235,74 @@ mod tests {
     use serde_json::{json, Value};
     use tower::ServiceExt;
 
-    use crate::{endpoints::Todo, provider::MockTodoProvider};
+    use crate::{
+        changes::ChangeKind,
+        cors::CorsConfig,
+        endpoints::{
+            BatchMode, BatchOperation, BatchOutcome, BatchResult, DeadLetter, Todo, TodoAction,
+            TodoRevision, TrashedTodo, Webhook,
+        },
+        provider::{MockBackupProvider, MockTodoProvider, MockWebhookProvider},
+        rate_limit::{Budget, RateLimitConfig},
+    };
 
     use super::*;
 
     #[derive(Clone)]
     struct MockAppState {
         provider: Arc<MockTodoProvider>,
+        webhooks: Arc<MockWebhookProvider>,
+        backups: Arc<MockBackupProvider>,
+        changes: ChangeFeed,
+        rate_limiter: RateLimiter,
+        config: AppConfig,
     }
 
     impl MockAppState {
         pub fn new(provider: MockTodoProvider) -> Self {
+            Self::with_webhooks(provider, MockWebhookProvider::new())
+        }
+
+        pub fn with_webhooks(provider: MockTodoProvider, webhooks: MockWebhookProvider) -> Self {
             Self {
                 provider: provider.into(),
+                webhooks: webhooks.into(),
+                backups: MockBackupProvider::new().into(),
+                changes: ChangeFeed::default(),
+                rate_limiter: RateLimiter::default(),
+                config: AppConfig::default(),
             }
         }
     }
 
     impl AppState for MockAppState {
         type P = MockTodoProvider;
+        type W = MockWebhookProvider;
+        type B = MockBackupProvider;
 
         fn provider(&self) -> &Self::P {
             self.provider.as_ref()
         }
+
+        fn webhooks(&self) -> &Self::W {
+            self.webhooks.as_ref()
+        }
+
+        fn backups(&self) -> &Self::B {
+            self.backups.as_ref()
+        }
+
+        fn changes(&self) -> &ChangeFeed {
+            &self.changes
+        }
+
+        fn rate_limiter(&self) -> &RateLimiter {
+            &self.rate_limiter
+        }
+
+        fn config(&self) -> &AppConfig {
+            &self.config
+        }
     }
 
     #[tokio::test]
@@ -212,7 +418,9 @@ mod tests {
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
         let body = response.into_body().collect().await.unwrap().to_bytes();
-        assert!(body.is_empty());
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "not found");
+        assert!(json["request_id"].is_string());
     }
 
     #[tokio::test]
@@ -221,8 +429,8 @@ mod tests {
         provider
             .expect_add_todo()
             .times(1)
-            .with(eq("test 1"))
-            .returning(|_| {
+            .with(eq("test 1"), eq("anonymous"))
+            .returning(|_, _| {
                 Ok(Todo {
                     id: 1,
                     description: "test 1".to_string(),
@@ -270,13 +478,13 @@ mod tests {
         provider
             .expect_update_todo()
             .times(1)
-            .with(eq(1), eq("test 1"), eq(true))
-            .returning(|_, _, _| {
-                Ok(Todo {
+            .with(eq(1), eq("test 1"), eq(true), eq("anonymous"))
+            .returning(|_, _, _, _| {
+                Ok(Some(Todo {
                     id: 1,
                     description: "test 1".to_string(),
                     done: true,
-                })
+                }))
             });
 
         let state = MockAppState::new(provider);
@@ -312,4 +520,2073 @@ mod tests {
             })
         );
     }
+
+    #[tokio::test]
+    async fn test_update_todo_not_found() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_update_todo()
+            .times(1)
+            .returning(|_, _, _, _| Ok(None));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::PUT)
+                    .uri("/todos/1")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "description": "test 1",
+                            "done": true,
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_delete_todo() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_delete_todo()
+            .times(1)
+            .with(eq(1), eq("anonymous"))
+            .returning(|_, _| {
+                Ok(Some(Todo {
+                    id: 1,
+                    description: "test 1".to_string(),
+                    done: false,
+                }))
+            });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::DELETE)
+                    .uri("/todos/1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_delete_todo_not_found() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_delete_todo()
+            .times(1)
+            .with(eq(1), eq("anonymous"))
+            .returning(|_, _| Ok(None));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::DELETE)
+                    .uri("/todos/1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_trash() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_get_trash().times(1).returning(|| {
+            Ok(vec![TrashedTodo {
+                id: 1,
+                description: "test 1".to_string(),
+                done: false,
+                deleted_at: 1700000000,
+            }])
+        });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/trash")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!([{
+                "id": 1,
+                "description": "test 1",
+                "done": false,
+                "deleted_at": 1700000000
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_todo() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_restore_todo()
+            .times(1)
+            .with(eq(1), eq("anonymous"))
+            .returning(|_, _| {
+                Ok(Some(Todo {
+                    id: 1,
+                    description: "test 1".to_string(),
+                    done: false,
+                }))
+            });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/trash/1/restore")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "id": 1,
+                "description": "test 1",
+                "done": false
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_trash() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_purge_trash()
+            .times(1)
+            .with(eq(Duration::ZERO), eq("anonymous"))
+            .returning(|_, _| Ok(2));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::DELETE)
+                    .uri("/trash")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json, json!({ "purged": 2 }));
+    }
+
+    #[tokio::test]
+    async fn test_update_todo_actor() {
+        let mut provider = MockTodoProvider::new();
+
+        provider
+            .expect_update_todo()
+            .times(1)
+            .with(eq(1), eq("test 1"), eq(true), eq("alice"))
+            .returning(|_, _, _, _| {
+                Ok(Some(Todo {
+                    id: 1,
+                    description: "test 1".to_string(),
+                    done: true,
+                }))
+            });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::PUT)
+                    .uri("/todos/1")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header("X-Actor", "alice")
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "description": "test 1",
+                            "done": true,
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_get_history() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_history()
+            .times(1)
+            .with(eq(1))
+            .returning(|_| {
+                Ok(vec![
+                    TodoRevision {
+                        revision: 1,
+                        action: TodoAction::Created,
+                        old_description: None,
+                        old_done: None,
+                        new_description: Some("test 1".to_string()),
+                        new_done: Some(false),
+                        actor: "alice".to_string(),
+                        created_at: 1700000000,
+                    },
+                    TodoRevision {
+                        revision: 2,
+                        action: TodoAction::Updated,
+                        old_description: Some("test 1".to_string()),
+                        old_done: Some(false),
+                        new_description: Some("test 1".to_string()),
+                        new_done: Some(true),
+                        actor: "bob".to_string(),
+                        created_at: 1700000060,
+                    },
+                ])
+            });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos/1/history")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!([{
+                "revision": 1,
+                "action": "created",
+                "old_description": null,
+                "old_done": null,
+                "new_description": "test 1",
+                "new_done": false,
+                "actor": "alice",
+                "created_at": 1700000000
+            }, {
+                "revision": 2,
+                "action": "updated",
+                "old_description": "test 1",
+                "old_done": false,
+                "new_description": "test 1",
+                "new_done": true,
+                "actor": "bob",
+                "created_at": 1700000060
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_history_not_found() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_history()
+            .times(1)
+            .with(eq(1))
+            .returning(|_| Ok(vec![]));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos/1/history")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_restore_revision() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_history()
+            .times(1)
+            .with(eq(1))
+            .returning(|_| {
+                Ok(vec![
+                    TodoRevision {
+                        revision: 1,
+                        action: TodoAction::Created,
+                        old_description: None,
+                        old_done: None,
+                        new_description: Some("test 1".to_string()),
+                        new_done: Some(false),
+                        actor: "alice".to_string(),
+                        created_at: 1700000000,
+                    },
+                    TodoRevision {
+                        revision: 2,
+                        action: TodoAction::Updated,
+                        old_description: Some("test 1".to_string()),
+                        old_done: Some(false),
+                        new_description: Some("test 2".to_string()),
+                        new_done: Some(true),
+                        actor: "bob".to_string(),
+                        created_at: 1700000060,
+                    },
+                ])
+            });
+        provider
+            .expect_update_todo()
+            .times(1)
+            .with(eq(1), eq("test 1"), eq(false), eq("anonymous"))
+            .returning(|_, _, _, _| {
+                Ok(Some(Todo {
+                    id: 1,
+                    description: "test 1".to_string(),
+                    done: false,
+                }))
+            });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/1/revisions/1/restore")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "id": 1,
+                "description": "test 1",
+                "done": false
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_revision_not_found() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_history()
+            .times(1)
+            .with(eq(1))
+            .returning(|_| {
+                Ok(vec![TodoRevision {
+                    revision: 1,
+                    action: TodoAction::Created,
+                    old_description: None,
+                    old_done: None,
+                    new_description: Some("test 1".to_string()),
+                    new_done: Some(false),
+                    actor: "alice".to_string(),
+                    created_at: 1700000000,
+                }])
+            });
+        provider.expect_update_todo().never();
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/1/revisions/5/restore")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_restore_revision_deleted() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_history()
+            .times(1)
+            .with(eq(1))
+            .returning(|_| {
+                Ok(vec![TodoRevision {
+                    revision: 1,
+                    action: TodoAction::Created,
+                    old_description: None,
+                    old_done: None,
+                    new_description: Some("test 1".to_string()),
+                    new_done: Some(false),
+                    actor: "alice".to_string(),
+                    created_at: 1700000000,
+                }])
+            });
+        // Deleted since its history was read
+        provider
+            .expect_update_todo()
+            .times(1)
+            .returning(|_, _, _, _| Ok(None));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/1/revisions/1/restore")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_apply_batch() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_apply_batch()
+            .times(1)
+            .with(
+                eq(vec![
+                    BatchOperation::Create {
+                        description: "test 1".to_string(),
+                        done: false,
+                    },
+                    BatchOperation::Delete { id: 2 },
+                ]),
+                eq(BatchMode::BestEffort),
+                eq("anonymous"),
+            )
+            .returning(|_, _, _| {
+                Ok(BatchOutcome {
+                    committed: true,
+                    results: vec![
+                        BatchResult::Ok {
+                            todo: Todo {
+                                id: 1,
+                                description: "test 1".to_string(),
+                                done: false,
+                            },
+                        },
+                        BatchResult::NotFound,
+                    ],
+                })
+            });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/batch")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "mode": "best_effort",
+                            "operations": [
+                                { "op": "create", "description": "test 1" },
+                                { "op": "delete", "id": 2 }
+                            ]
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "committed": true,
+                "results": [{
+                    "status": "ok",
+                    "todo": {
+                        "id": 1,
+                        "description": "test 1",
+                        "done": false
+                    }
+                }, {
+                    "status": "not_found"
+                }]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_batch_rolled_back() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_apply_batch()
+            .times(1)
+            .with(
+                eq(vec![
+                    BatchOperation::Delete { id: 1 },
+                    BatchOperation::Delete { id: 2 },
+                ]),
+                eq(BatchMode::Atomic),
+                eq("anonymous"),
+            )
+            .returning(|_, _, _| {
+                Ok(BatchOutcome {
+                    committed: false,
+                    results: vec![BatchResult::NotFound, BatchResult::Skipped],
+                })
+            });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/batch")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "operations": [
+                                { "op": "delete", "id": 1 },
+                                { "op": "delete", "id": 2 }
+                            ]
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "committed": false,
+                "results": [{ "status": "not_found" }, { "status": "skipped" }]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_csv() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_get_todos().times(1).returning(|| {
+            Ok(vec![
+                Todo {
+                    id: 1,
+                    description: "test 1".to_string(),
+                    done: false,
+                },
+                Todo {
+                    id: 2,
+                    description: "test, \"2\"".to_string(),
+                    done: true,
+                },
+            ])
+        });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos/export.csv")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            mime::TEXT_CSV_UTF_8.as_ref()
+        );
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            body,
+            "id,description,done\n1,test 1,false\n2,\"test, \"\"2\"\"\",true\n"
+        );
+    }
+
+    /// Expects one atomic batch creating a todo for each of `rows`
+    fn expect_import(provider: &mut MockTodoProvider, rows: &[(&'static str, bool)]) {
+        let operations: Vec<_> = rows
+            .iter()
+            .map(|(description, done)| BatchOperation::Create {
+                description: description.to_string(),
+                done: *done,
+            })
+            .collect();
+        let results = rows
+            .iter()
+            .zip(1..)
+            .map(|((description, done), id)| BatchResult::Ok {
+                todo: Todo {
+                    id,
+                    description: description.to_string(),
+                    done: *done,
+                },
+            })
+            .collect::<Vec<_>>();
+        provider
+            .expect_apply_batch()
+            .times(1)
+            .with(eq(operations), eq(BatchMode::Atomic), eq("anonymous"))
+            .returning(move |_, _, _| {
+                Ok(BatchOutcome {
+                    committed: true,
+                    results: results.clone(),
+                })
+            });
+    }
+
+    #[tokio::test]
+    async fn test_import_csv() {
+        let mut provider = MockTodoProvider::new();
+        expect_import(&mut provider, &[("test 1", false), ("test 2", true)]);
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/import?description_column=Task&done_column=Completed")
+                    .header(http::header::CONTENT_TYPE, mime::TEXT_CSV.as_ref())
+                    .body(Body::from(
+                        "Task,Owner,Completed\ntest 1,alice,no\ntest 2,bob,yes\n",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "dry_run": false,
+                "imported": 2,
+                "errors": []
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_failed() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_apply_batch().times(1).returning(|_, _, _| {
+            Ok(BatchOutcome {
+                committed: false,
+                results: vec![
+                    BatchResult::RolledBack,
+                    BatchResult::Failed {
+                        error: "database is full".to_string(),
+                    },
+                ],
+            })
+        });
+
+        let state = MockAppState::new(provider);
+        let (_, mut changes) = state.changes().subscribe(None);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/import")
+                    .header(http::header::CONTENT_TYPE, mime::TEXT_CSV.as_ref())
+                    .body(Body::from("description\ntest 1\ntest 2\n"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_import_csv_dry_run() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_add_todo().never();
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/import?dry_run=true")
+                    .header(http::header::CONTENT_TYPE, mime::TEXT_CSV.as_ref())
+                    .body(Body::from("description,done\ntest 1,false\ntest 2,true\n"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "dry_run": true,
+                "imported": 2,
+                "errors": []
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_csv_row_errors() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_add_todo().never();
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/import")
+                    .header(http::header::CONTENT_TYPE, mime::TEXT_CSV.as_ref())
+                    .body(Body::from(
+                        "description,done\ntest 1,false\n,false\ntest 3,maybe\n",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "dry_run": false,
+                "imported": 0,
+                "errors": [{
+                    "row": 3,
+                    "message": "description is empty"
+                }, {
+                    "row": 4,
+                    "message": "\"maybe\" is not a valid done value"
+                }]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_missing_column() {
+        let provider = MockTodoProvider::new();
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/import")
+                    .header(http::header::CONTENT_TYPE, mime::TEXT_CSV.as_ref())
+                    .body(Body::from("task\ntest 1\n"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_import_unsupported_media_type() {
+        let provider = MockTodoProvider::new();
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/import")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from("[]"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_export_ical() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_get_todos().times(1).returning(|| {
+            Ok(vec![Todo {
+                id: 1,
+                description: "test 1".to_string(),
+                done: true,
+            }])
+        });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos.ics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            "text/calendar; charset=utf-8"
+        );
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(body.contains("\r\nUID:todo-1@axum-sqlx-mockall-todos\r\n"));
+        assert!(body.contains("\r\nSUMMARY:test 1\r\n"));
+        assert!(body.contains("\r\nSTATUS:COMPLETED\r\n"));
+        assert!(body.ends_with("END:VCALENDAR\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_import_ical() {
+        let mut provider = MockTodoProvider::new();
+        expect_import(&mut provider, &[("test 1", true)]);
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/import")
+                    .header(http::header::CONTENT_TYPE, "text/calendar")
+                    .body(Body::from(
+                        "BEGIN:VCALENDAR\r\n\
+                        BEGIN:VTODO\r\n\
+                        SUMMARY:test 1\r\n\
+                        STATUS:COMPLETED\r\n\
+                        END:VTODO\r\n\
+                        END:VCALENDAR\r\n",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "dry_run": false,
+                "imported": 1,
+                "errors": []
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_todos_todo_txt() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_get_todos().times(1).returning(|| {
+            Ok(vec![
+                Todo {
+                    id: 1,
+                    description: "(A) test 1 +project @context".to_string(),
+                    done: false,
+                },
+                Todo {
+                    id: 2,
+                    description: "test 2".to_string(),
+                    done: true,
+                },
+            ])
+        });
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos")
+                    .header(http::header::ACCEPT, "text/plain")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            mime::TEXT_PLAIN_UTF_8.as_ref()
+        );
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "(A) test 1 +project @context\nx test 2\n");
+    }
+
+    #[tokio::test]
+    async fn test_get_todos_prefers_json() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_todos()
+            .times(1)
+            .returning(|| Ok(vec![]));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos")
+                    .header(http::header::ACCEPT, "text/plain;q=0.5, application/json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            mime::APPLICATION_JSON.as_ref()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_todo_txt() {
+        let mut provider = MockTodoProvider::new();
+        expect_import(
+            &mut provider,
+            &[("(A) test 1 +project", false), ("test 2 @context", true)],
+        );
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/import")
+                    .header(http::header::CONTENT_TYPE, mime::TEXT_PLAIN.as_ref())
+                    .body(Body::from(
+                        "(A) 2024-01-01 test 1 +project\nx 2024-01-02 test 2 @context\n",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "dry_run": false,
+                "imported": 2,
+                "errors": []
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_todo_publishes_change() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_add_todo()
+            .times(1)
+            .with(eq("test 1"), eq("anonymous"))
+            .returning(|_, _| {
+                Ok(Todo {
+                    id: 1,
+                    description: "test 1".to_string(),
+                    done: false,
+                })
+            });
+
+        let state = MockAppState::new(provider);
+        let (_, mut changes) = state.changes().subscribe(None);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "description": "test 1",
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.id, 1);
+        assert_eq!(change.kind, ChangeKind::Created);
+        assert_eq!(change.todo.id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_todo_events_resume() {
+        let provider = MockTodoProvider::new();
+
+        let state = MockAppState::new(provider);
+        for id in 1..=3 {
+            state.changes().publish(
+                ChangeKind::Updated,
+                Todo {
+                    id,
+                    description: format!("test {id}"),
+                    done: true,
+                },
+            );
+        }
+
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos/events")
+                    .header("Last-Event-ID", "1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            mime::TEXT_EVENT_STREAM.as_ref()
+        );
+
+        let mut body = response.into_body();
+        let mut events = String::new();
+        while events.matches("\n\n").count() < 2 {
+            let frame = body.frame().await.unwrap().unwrap();
+            events.push_str(std::str::from_utf8(frame.data_ref().unwrap()).unwrap());
+        }
+
+        assert_eq!(
+            events,
+            "id: 2\n\
+            event: updated\n\
+            data: {\"id\":2,\"description\":\"test 2\",\"done\":true}\n\n\
+            id: 3\n\
+            event: updated\n\
+            data: {\"id\":3,\"description\":\"test 3\",\"done\":true}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_webhook() {
+        let mut webhooks = MockWebhookProvider::new();
+        webhooks
+            .expect_add_webhook()
+            .with(eq("http://example.com/hook"), eq("s3cret"))
+            .times(1)
+            .returning(|url, secret| {
+                Ok(Webhook {
+                    id: 1,
+                    url: url.to_string(),
+                    secret: secret.to_string(),
+                    created_at: 1700000000,
+                })
+            });
+
+        let mut state = MockAppState::with_webhooks(MockTodoProvider::new(), webhooks);
+        state.config.admin_token = Some("secret".to_string());
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .header(http::header::AUTHORIZATION, "Bearer secret")
+                    .method(http::Method::POST)
+                    .uri("/webhooks")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        r#"{"url": "http://example.com/hook", "secret": "s3cret"}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({"id": 1, "url": "http://example.com/hook", "created_at": 1700000000})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_webhook_invalid_url() {
+        let webhooks = MockWebhookProvider::new();
+
+        let mut state = MockAppState::with_webhooks(MockTodoProvider::new(), webhooks);
+        state.config.admin_token = Some("secret".to_string());
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .header(http::header::AUTHORIZATION, "Bearer secret")
+                    .method(http::Method::POST)
+                    .uri("/webhooks")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        r#"{"url": "ftp://example.com/hook", "secret": "s3cret"}"#,
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_delete_webhook_not_found() {
+        let mut webhooks = MockWebhookProvider::new();
+        webhooks
+            .expect_delete_webhook()
+            .with(eq(1))
+            .times(1)
+            .returning(|_| Ok(false));
+
+        let mut state = MockAppState::with_webhooks(MockTodoProvider::new(), webhooks);
+        state.config.admin_token = Some("secret".to_string());
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .header(http::header::AUTHORIZATION, "Bearer secret")
+                    .method(http::Method::DELETE)
+                    .uri("/webhooks/1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_get_dead_letters() {
+        let mut webhooks = MockWebhookProvider::new();
+        webhooks.expect_get_dead_letters().times(1).returning(|| {
+            Ok(vec![DeadLetter {
+                id: 3,
+                webhook_id: 1,
+                url: "http://example.com/hook".to_string(),
+                payload: "{}".to_string(),
+                attempts: 8,
+                last_error: Some("receiver responded with 500".to_string()),
+                created_at: 1700000000,
+            }])
+        });
+
+        let mut state = MockAppState::with_webhooks(MockTodoProvider::new(), webhooks);
+        state.config.admin_token = Some("secret".to_string());
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .header(http::header::AUTHORIZATION, "Bearer secret")
+                    .uri("/webhooks/dead-letters")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!([{
+                "id": 3,
+                "webhook_id": 1,
+                "url": "http://example.com/hook",
+                "payload": "{}",
+                "attempts": 8,
+                "last_error": "receiver responded with 500",
+                "created_at": 1700000000
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_dead_letter() {
+        let mut webhooks = MockWebhookProvider::new();
+        webhooks
+            .expect_retry_dead_letter()
+            .with(eq(3))
+            .times(1)
+            .returning(|_| Ok(true));
+
+        let mut state = MockAppState::with_webhooks(MockTodoProvider::new(), webhooks);
+        state.config.admin_token = Some("secret".to_string());
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .header(http::header::AUTHORIZATION, "Bearer secret")
+                    .method(http::Method::POST)
+                    .uri("/webhooks/dead-letters/3/retry")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn test_webhooks_unauthorized() {
+        let mut state =
+            MockAppState::with_webhooks(MockTodoProvider::new(), MockWebhookProvider::new());
+        state.config.admin_token = Some("secret".to_string());
+        let app = router(state);
+
+        for (method, uri) in [
+            (http::Method::GET, "/webhooks"),
+            (http::Method::POST, "/webhooks"),
+            (http::Method::DELETE, "/webhooks/1"),
+            (http::Method::GET, "/webhooks/dead-letters"),
+            (http::Method::POST, "/webhooks/dead-letters/3/retry"),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(method)
+                        .uri(uri)
+                        .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                        .body(Body::from(
+                            r#"{"url": "http://169.254.169.254/", "secret": "s3cret"}"#,
+                        ))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_graphql_query() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_get_todos().times(1).returning(|| {
+            Ok(vec![Todo {
+                id: 1,
+                description: "test 1".to_string(),
+                done: false,
+            }])
+        });
+        provider
+            .expect_get_todo()
+            .with(eq(2))
+            .times(1)
+            .returning(|_| Ok(None));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/graphql")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "query": "{ todos { id description } todo(id: 2) { id } }",
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "data": {
+                    "todos": [{ "id": 1, "description": "test 1" }],
+                    "todo": null
+                }
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_graphql_add_todo() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_add_todo()
+            .times(1)
+            .with(eq("test 1"), eq("alice"))
+            .returning(|_, _| {
+                Ok(Todo {
+                    id: 1,
+                    description: "test 1".to_string(),
+                    done: false,
+                })
+            });
+
+        let state = MockAppState::new(provider);
+        let (_, mut changes) = state.changes().subscribe(None);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/graphql")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header("X-Actor", "alice")
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "query": "mutation { addTodo(description: \"test 1\") { id done } }",
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({ "data": { "addTodo": { "id": 1, "done": false } } })
+        );
+
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.kind, ChangeKind::Created);
+        assert_eq!(change.todo.id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_graphql_update_todo_not_found() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_update_todo()
+            .with(eq(1), eq("test"), eq(true), eq("anonymous"))
+            .times(1)
+            .returning(|_, _, _, _| Ok(None));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/graphql")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_vec(&json!({
+                            "query": "mutation { updateTodo(id: 1, description: \"test\", done: true) { id } }",
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["data"], Value::Null);
+        assert_eq!(json["errors"][0]["message"], "todo 1 not found");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_add_todo().times(1).returning(|_, _| {
+            Ok(Todo {
+                id: 1,
+                description: "test 1".to_string(),
+                done: false,
+            })
+        });
+
+        let mut state = MockAppState::new(provider);
+        state.rate_limiter = RateLimiter::new(RateLimitConfig {
+            read: Budget::per_minute(10),
+            write: Budget::per_minute(1),
+            ..RateLimitConfig::default()
+        });
+        let app = router(state);
+
+        let add_todo = || {
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/todos")
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(r#"{"description": "test 1"}"#))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(add_todo()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app.oneshot(add_todo()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "60");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_todo()
+            .with(eq(1))
+            .times(1)
+            .returning(|_| Err(ProviderError(anyhow::anyhow!("database is locked"))));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos/1")
+                    .header("X-Request-Id", "abc-123")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.headers()["x-request-id"], "abc-123");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            json,
+            json!({ "error": "internal error", "request_id": "abc-123" })
+        );
+    }
+
+    /// Collects what is logged, for checking the fields logged with it
+    #[derive(Clone, Default)]
+    struct Logs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_id_logged() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_todo()
+            .times(1)
+            .returning(|_| Err(ProviderError(anyhow::anyhow!("database is locked"))));
+
+        let app = router(MockAppState::new(provider));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos/1")
+                    .header("X-Request-Id", "abc-123")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let error = logs
+            .lines()
+            .find(|line| line.contains("ERROR") && line.contains("database is locked"))
+            .unwrap_or_else(|| panic!("no error was logged: {logs}"));
+        assert!(error.contains("request_id=\"abc-123\""), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_request_id_generated() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_todos()
+            .times(1)
+            .returning(|| Ok(vec![]));
+
+        let state = MockAppState::new(provider);
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let id = response.headers()["x-request-id"].to_str().unwrap();
+        assert_eq!(id.len(), 36, "{id} is not a UUID");
+    }
+
+    fn cors_state() -> MockAppState {
+        let mut state = MockAppState::new(MockTodoProvider::new());
+        state.config.cors = CorsConfig {
+            allowed_origins: vec![
+                "https://app.example.com".to_string(),
+                "https://*.example.org".to_string(),
+            ],
+            allow_credentials: true,
+            max_age: Some(Duration::from_secs(600)),
+            ..CorsConfig::default()
+        };
+        state
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(http::Method::OPTIONS)
+            .uri("/todos")
+            .header(http::header::ORIGIN, origin)
+            .header(http::header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(
+                http::header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "content-type,x-actor",
+            )
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight_allowed() {
+        let app = router(cors_state());
+
+        for origin in ["https://app.example.com", "https://staging.example.org"] {
+            let response = app.clone().oneshot(preflight(origin)).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            let headers = response.headers();
+            assert_eq!(headers[http::header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+            assert_eq!(
+                headers[http::header::ACCESS_CONTROL_ALLOW_METHODS],
+                "GET,POST,PUT,DELETE"
+            );
+            assert!(headers[http::header::ACCESS_CONTROL_ALLOW_HEADERS]
+                .to_str()
+                .unwrap()
+                .contains("x-actor"));
+            assert_eq!(
+                headers[http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS],
+                "true"
+            );
+            assert_eq!(headers[http::header::ACCESS_CONTROL_MAX_AGE], "600");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cors_preflight_rejected() {
+        let app = router(cors_state());
+
+        for origin in [
+            "https://evil.com",
+            "https://example.org",
+            "http://app.example.com",
+        ] {
+            let response = app.clone().oneshot(preflight(origin)).await.unwrap();
+
+            assert!(
+                !response
+                    .headers()
+                    .contains_key(http::header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                "{origin} was allowed"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cors_simple_request() {
+        let mut state = cors_state();
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_todos()
+            .times(1)
+            .returning(|| Ok(vec![]));
+        state.provider = provider.into();
+
+        let app = router(state);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos")
+                    .header(http::header::ORIGIN, "https://app.example.com")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert!(
+            response.headers()[http::header::ACCESS_CONTROL_EXPOSE_HEADERS]
+                .to_str()
+                .unwrap()
+                .contains("x-request-id")
+        );
+    }
+
+    fn many_todos() -> Vec<Todo> {
+        (1..=100)
+            .map(|id| Todo {
+                id,
+                description: format!("test {id}"),
+                done: false,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_response_compression() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_get_todos()
+            .times(3)
+            .returning(|| Ok(many_todos()));
+        provider.expect_get_todo().times(1).returning(|id| {
+            Ok(Some(Todo {
+                id,
+                description: "test".to_string(),
+                done: false,
+            }))
+        });
+
+        let app = router(MockAppState::new(provider));
+
+        for encoding in ["gzip", "br", "zstd"] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/todos")
+                        .header(http::header::ACCEPT_ENCODING, encoding)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[http::header::CONTENT_ENCODING], encoding);
+        }
+
+        // Small responses are not compressed
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos/1")
+                    .header(http::header::ACCEPT_ENCODING, "gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response
+            .headers()
+            .contains_key(http::header::CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn test_request_decompression() {
+        use std::io::Write;
+
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_add_todo()
+            .with(eq("test 1"), eq("anonymous"))
+            .times(1)
+            .returning(|description, _| {
+                Ok(Todo {
+                    id: 1,
+                    description: description.to_string(),
+                    done: false,
+                })
+            });
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(br#"{"description": "test 1"}"#).unwrap();
+        let body = encoder.finish().unwrap();
+
+        let app = router(MockAppState::new(provider));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header(http::header::CONTENT_ENCODING, "gzip")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_add_todo().never();
+
+        let mut state = MockAppState::new(provider);
+        state.config.body_limits.default = 32;
+        let app = router(state);
+
+        let description = "x".repeat(64);
+        let body = json!({ "description": description }).to_string();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(body.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "request body is larger than 32 bytes");
+
+        // Bodies without a length are cut off at the limit
+        let stream = futures_util::stream::iter([Ok::<_, std::io::Error>(description)]);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from_stream(stream))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_body_limit_per_route() {
+        let mut provider = MockTodoProvider::new();
+        provider
+            .expect_apply_batch()
+            .times(1)
+            .returning(|operations, _, _| {
+                Ok(BatchOutcome {
+                    committed: true,
+                    results: vec![
+                        BatchResult::Ok {
+                            todo: Todo {
+                                id: 1,
+                                description: "large".to_string(),
+                                done: false,
+                            },
+                        };
+                        operations.len()
+                    ],
+                })
+            });
+
+        let mut state = MockAppState::new(provider);
+        state.config.body_limits.default = 32;
+        state
+            .config
+            .body_limits
+            .routes
+            .insert("/todos/import".to_string(), 4 * 1024 * 1024);
+        let app = router(state);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/todos/import")
+                    .header(http::header::CONTENT_TYPE, mime::TEXT_PLAIN_UTF_8.as_ref())
+                    // Larger than axum's default limit of 2MB
+                    .body(Body::from(format!("{}\n", "x".repeat(3 * 1024 * 1024))))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    fn backup_request(token: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(http::Method::POST)
+            .uri("/admin/backup");
+        if let Some(token) = token {
+            request = request.header(http::header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_backup() {
+        let mut backups = MockBackupProvider::new();
+        backups.expect_backup().times(1).returning(|path| {
+            std::fs::write(path, "snapshot").unwrap();
+            Ok(())
+        });
+
+        let mut state = MockAppState::new(MockTodoProvider::new());
+        state.backups = Arc::new(backups);
+        state.config.admin_token = Some("secret".to_string());
+        let app = router(state);
+
+        let response = app.oneshot(backup_request(Some("secret"))).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            "application/vnd.sqlite3"
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "snapshot");
+    }
+
+    #[tokio::test]
+    async fn test_backup_unauthorized() {
+        let mut state = MockAppState::new(MockTodoProvider::new());
+        state.config.admin_token = Some("secret".to_string());
+
+        for token in [None, Some("wrong"), Some("secret2")] {
+            let response = router(state.clone())
+                .oneshot(backup_request(token))
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[http::header::WWW_AUTHENTICATE], "Bearer");
+        }
+
+        // Admin endpoints are disabled without a token
+        state.config.admin_token = None;
+        let response = router(state)
+            .oneshot(backup_request(Some("")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_service_unavailable() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_get_todos().times(1).returning(|| {
+            Err(ProviderError(
+                Unavailable {
+                    retry_after: Duration::from_millis(2500),
+                }
+                .into(),
+            ))
+        });
+
+        let app = router(MockAppState::new(provider));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/todos")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "3");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "service unavailable");
+    }
+
+    #[tokio::test]
+    async fn test_graphql_service_unavailable() {
+        let mut provider = MockTodoProvider::new();
+        provider.expect_get_todos().times(1).returning(|| {
+            Err(ProviderError(
+                Unavailable {
+                    retry_after: Duration::from_secs(10),
+                }
+                .into(),
+            ))
+        });
+
+        let app = router(MockAppState::new(provider));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/graphql")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(r#"{"query": "{ todos { id } }"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["errors"][0]["message"], "service unavailable");
+        assert_eq!(
+            json["errors"][0]["extensions"],
+            json!({ "code": "SERVICE_UNAVAILABLE", "retryAfter": 10 })
+        );
+    }
 }
@@ -0,0 +1,182 @@
+//! `todo`, a command-line client for a running todo server.
+//!
+//! The server's URL and token are taken from the command line, then the `TODO_URL` and
+//! `TODO_TOKEN` variables, then a TOML config file such as:
+//!
+//! ```toml
+//! url = "https://todos.example.com"
+//! token = "secret"
+//! ```
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use axum_sqlx_mockall_todos::{
+    client::TodoClient,
+    endpoints::{Todo, TodoAdd, TodoUpdate},
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+const DEFAULT_URL: &str = "http://localhost:3000";
+
+#[derive(Parser)]
+#[command(name = "todo", about = "Manage todos on a todo server")]
+struct Cli {
+    /// The server's URL
+    #[arg(long, env = "TODO_URL", global = true)]
+    url: Option<String>,
+    /// A token to authenticate with
+    #[arg(long, env = "TODO_TOKEN", global = true, hide_env_values = true)]
+    token: Option<String>,
+    /// The config file, by default `todo/config.toml` in the user's config directory
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[arg(long, short, value_enum, default_value_t = Output::Table, global = true)]
+    output: Output,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List todos
+    Ls,
+    /// Add a todo
+    Add { description: String },
+    /// Mark a todo as done
+    Done { id: i64 },
+    /// Change a todo's description
+    Edit { id: i64, description: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Output {
+    Table,
+    Json,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    url: Option<String>,
+    token: Option<String>,
+}
+
+impl Config {
+    /// Reads the config file at `path`, or the default one if it exists
+    fn read(path: Option<&Path>) -> anyhow::Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_config_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Config::default())
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+    }
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("todo").join("config.toml"))
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config = Config::read(cli.config.as_deref())?;
+
+    let url = cli.url.or(config.url);
+    let client = TodoClient::new(
+        url.as_deref().unwrap_or(DEFAULT_URL),
+        cli.token.or(config.token),
+    )?;
+
+    let todos = match cli.command {
+        Command::Ls => client.get_todos().await?,
+        Command::Add { description } => vec![client.add_todo(&TodoAdd { description }).await?],
+        Command::Done { id } => {
+            let todo = client.get_todo(id).await?;
+            let update = TodoUpdate {
+                description: todo.description,
+                done: true,
+            };
+            vec![client.update_todo(id, &update).await?]
+        }
+        Command::Edit { id, description } => {
+            let todo = client.get_todo(id).await?;
+            let update = TodoUpdate {
+                description,
+                done: todo.done,
+            };
+            vec![client.update_todo(id, &update).await?]
+        }
+    };
+
+    match cli.output {
+        Output::Table => print!("{}", table(&todos)),
+        Output::Json => println!("{}", serde_json::to_string_pretty(&todos)?),
+    }
+    Ok(())
+}
+
+fn table(todos: &[Todo]) -> String {
+    let ids: Vec<String> = todos.iter().map(|todo| todo.id.to_string()).collect();
+    let width = ids
+        .iter()
+        .map(String::len)
+        .max()
+        .unwrap_or(0)
+        .max("ID".len());
+
+    let mut table = format!("{:<width$}  DONE  DESCRIPTION\n", "ID");
+    for (id, todo) in ids.iter().zip(todos) {
+        let done = if todo.done { "[x]" } else { "[ ]" };
+        table.push_str(&format!("{id:>width$}  {done:<4}  {}\n", todo.description));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let todos = vec![
+            Todo {
+                id: 9,
+                description: "Buy milk".to_string(),
+                done: true,
+            },
+            Todo {
+                id: 10,
+                description: "Walk the dog".to_string(),
+                done: false,
+            },
+        ];
+
+        assert_eq!(
+            table(&todos),
+            "ID  DONE  DESCRIPTION\n 9  [x]   Buy milk\n10  [ ]   Walk the dog\n"
+        );
+        assert_eq!(table(&[]), "ID  DONE  DESCRIPTION\n");
+    }
+}
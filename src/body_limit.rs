@@ -0,0 +1,94 @@
+//! Limits on the size of request bodies, which can be set for each route. Limits apply to
+//! bodies after they are decompressed.
+
+use std::{collections::HashMap, error::Error};
+
+use axum::{
+    body::{self, Body},
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use http_body_util::LengthLimitError;
+
+use crate::app::{AppError, AppState};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BodyLimitConfig {
+    /// The limit in bytes for routes without their own
+    pub default: usize,
+    /// Limits in bytes for routes, keyed by their path as given to the router, such as
+    /// `/todos/:id`
+    pub routes: HashMap<String, usize>,
+}
+
+impl BodyLimitConfig {
+    pub fn limit(&self, route: Option<&str>) -> usize {
+        route
+            .and_then(|route| self.routes.get(route))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl Default for BodyLimitConfig {
+    fn default() -> Self {
+        BodyLimitConfig {
+            default: 64 * 1024,
+            routes: HashMap::from([
+                ("/todos/batch".to_string(), 1024 * 1024),
+                ("/todos/import".to_string(), 10 * 1024 * 1024),
+            ]),
+        }
+    }
+}
+
+/// Middleware rejecting requests with bodies over the route's limit with a 413
+pub async fn limit<A: AppState>(
+    State(state): State<A>,
+    route: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let limit = state
+        .config()
+        .body_limits
+        .limit(route.as_ref().map(MatchedPath::as_str));
+
+    let content_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > limit) {
+        return Err(AppError::PayloadTooLarge { limit });
+    }
+
+    // Bodies without a length, or that are decompressed, are only known to be too large
+    // once they have been read
+    let (parts, body) = request.into_parts();
+    let bytes = match body::to_bytes(body, limit).await {
+        Ok(bytes) => bytes,
+        Err(err) if is_length_limit(&err) => return Err(AppError::PayloadTooLarge { limit }),
+        Err(err) => {
+            return Err(AppError::BadRequest(format!(
+                "failed to read request body: {err}"
+            )))
+        }
+    };
+
+    Ok(next
+        .run(Request::from_parts(parts, Body::from(bytes)))
+        .await)
+}
+
+fn is_length_limit(err: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<LengthLimitError>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
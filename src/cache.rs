@@ -0,0 +1,434 @@
+//! Caching of todo reads, for when reads far outnumber writes.
+//!
+//! Every write through the cache clears it, so reads through the same cache never see a
+//! todo older than the last write. Writes made elsewhere, such as by another process, are
+//! seen once cached entries expire.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use tokio::time::Instant;
+
+use crate::{
+    endpoints::{BatchMode, BatchOperation, BatchOutcome, Todo, TodoRevision, TrashedTodo},
+    layer::ProviderLayer,
+    provider::{ProviderError, TodoProvider},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheConfig {
+    /// How long results are cached for
+    pub ttl: Duration,
+    /// How many todos are cached individually, besides the list of all todos
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            ttl: Duration::from_secs(5),
+            max_entries: 10_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Caches the results of `get_todos` and `get_todo` from the provider it wraps. Clones
+/// share the cache.
+#[derive(Clone)]
+pub struct CachedTodoProvider<P> {
+    inner: P,
+    config: CacheConfig,
+    cache: Arc<Mutex<Cache>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct Cache {
+    todos: Option<Entry<Vec<Todo>>>,
+    todo: HashMap<i64, Entry<Option<Todo>>>,
+    /// The cached todos ordered by when they expire, soonest first
+    expiries: BTreeSet<(Instant, i64)>,
+    /// Incremented by every write, so that reads which started before a write do not
+    /// cache what they read
+    generation: u64,
+}
+
+struct Entry<T> {
+    value: T,
+    expires: Instant,
+}
+
+impl<P> CachedTodoProvider<P> {
+    pub fn new(inner: P, config: CacheConfig) -> Self {
+        CachedTodoProvider {
+            inner,
+            config,
+            cache: Default::default(),
+            hits: Default::default(),
+            misses: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the cached value if there is one, or otherwise the generation to cache the
+    /// value read under
+    fn lookup<T: Clone>(&self, get: impl FnOnce(&Cache) -> Option<&Entry<T>>) -> Result<T, u64> {
+        let cache = self.cache.lock().unwrap();
+        match get(&cache) {
+            Some(entry) if entry.expires > Instant::now() => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Ok(entry.value.clone())
+            }
+            _ => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Err(cache.generation)
+            }
+        }
+    }
+
+    /// Caches a value read under `generation`, unless there has been a write since
+    fn store(&self, generation: u64, store: impl FnOnce(&mut Cache, Instant)) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.generation == generation {
+            store(&mut cache, Instant::now() + self.config.ttl);
+        }
+    }
+
+    fn invalidate(&self) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.todos = None;
+        cache.todo.clear();
+        cache.expiries.clear();
+        cache.generation += 1;
+    }
+}
+
+/// Wraps providers in a [`CachedTodoProvider`]
+#[derive(Clone, Copy, Debug)]
+pub struct CacheLayer {
+    config: CacheConfig,
+}
+
+impl CacheLayer {
+    pub fn new(config: CacheConfig) -> Self {
+        CacheLayer { config }
+    }
+}
+
+impl<P> ProviderLayer<P> for CacheLayer {
+    type Provider = CachedTodoProvider<P>;
+
+    fn layer(&self, inner: P) -> Self::Provider {
+        CachedTodoProvider::new(inner, self.config)
+    }
+}
+
+impl Cache {
+    /// Caches a todo, first dropping those soonest to expire if there is no room. Expired
+    /// todos are always the soonest.
+    fn insert_todo(&mut self, id: i64, entry: Entry<Option<Todo>>, max_entries: usize) {
+        if let Some(old) = self.todo.remove(&id) {
+            self.expiries.remove(&(old.expires, id));
+        }
+        while self.todo.len() >= max_entries {
+            let Some((_, oldest)) = self.expiries.pop_first() else {
+                return;
+            };
+            self.todo.remove(&oldest);
+        }
+        self.expiries.insert((entry.expires, id));
+        self.todo.insert(id, entry);
+    }
+}
+
+#[async_trait]
+impl<P: TodoProvider + Send + Sync> TodoProvider for CachedTodoProvider<P> {
+    async fn get_todos(&self) -> Result<Vec<Todo>, ProviderError> {
+        let generation = match self.lookup(|cache| cache.todos.as_ref()) {
+            Ok(todos) => return Ok(todos),
+            Err(generation) => generation,
+        };
+
+        let todos = self.inner.get_todos().await?;
+        self.store(generation, |cache, expires| {
+            cache.todos = Some(Entry {
+                value: todos.clone(),
+                expires,
+            });
+        });
+        Ok(todos)
+    }
+
+    async fn get_todo(&self, id: i64) -> Result<Option<Todo>, ProviderError> {
+        let generation = match self.lookup(|cache| cache.todo.get(&id)) {
+            Ok(todo) => return Ok(todo),
+            Err(generation) => generation,
+        };
+
+        let todo = self.inner.get_todo(id).await?;
+        let max_entries = self.config.max_entries;
+        self.store(generation, |cache, expires| {
+            if max_entries == 0 {
+                return;
+            }
+            cache.insert_todo(
+                id,
+                Entry {
+                    value: todo.clone(),
+                    expires,
+                },
+                max_entries,
+            );
+        });
+        Ok(todo)
+    }
+
+    async fn add_todo(&self, description: &str, actor: &str) -> Result<Todo, ProviderError> {
+        let result = self.inner.add_todo(description, actor).await;
+        self.invalidate();
+        result
+    }
+
+    async fn update_todo(
+        &self,
+        id: i64,
+        description: &str,
+        done: bool,
+        actor: &str,
+    ) -> Result<Option<Todo>, ProviderError> {
+        let result = self.inner.update_todo(id, description, done, actor).await;
+        self.invalidate();
+        result
+    }
+
+    async fn delete_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError> {
+        let result = self.inner.delete_todo(id, actor).await;
+        self.invalidate();
+        result
+    }
+
+    async fn get_trash(&self) -> Result<Vec<TrashedTodo>, ProviderError> {
+        self.inner.get_trash().await
+    }
+
+    async fn restore_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError> {
+        let result = self.inner.restore_todo(id, actor).await;
+        self.invalidate();
+        result
+    }
+
+    async fn purge_trash(&self, retention: Duration, actor: &str) -> Result<u64, ProviderError> {
+        // Only todos in the trash are removed, which are not cached
+        self.inner.purge_trash(retention, actor).await
+    }
+
+    async fn get_history(&self, id: i64) -> Result<Vec<TodoRevision>, ProviderError> {
+        self.inner.get_history(id).await
+    }
+
+    async fn apply_batch(
+        &self,
+        operations: Vec<BatchOperation>,
+        mode: BatchMode,
+        actor: &str,
+    ) -> Result<BatchOutcome, ProviderError> {
+        let result = self.inner.apply_batch(operations, mode, actor).await;
+        self.invalidate();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::provider::MockTodoProvider;
+
+    fn todo(id: i64) -> Todo {
+        Todo {
+            id,
+            description: format!("todo {id}"),
+            done: false,
+        }
+    }
+
+    fn config() -> CacheConfig {
+        CacheConfig {
+            ttl: Duration::from_secs(10),
+            max_entries: 2,
+        }
+    }
+
+    fn unwrap<T>(result: Result<T, ProviderError>) -> T {
+        result.unwrap_or_else(|ProviderError(err)| panic!("{err}"))
+    }
+
+    #[tokio::test]
+    async fn test_hits_skip_inner_provider() {
+        let mut inner = MockTodoProvider::new();
+        inner
+            .expect_get_todos()
+            .times(1)
+            .returning(|| Ok(vec![todo(1), todo(2)]));
+        inner
+            .expect_get_todo()
+            .with(eq(1))
+            .times(1)
+            .returning(|id| Ok(Some(todo(id))));
+        inner
+            .expect_get_todo()
+            .with(eq(3))
+            .times(1)
+            .returning(|_| Ok(None));
+
+        let cached = CachedTodoProvider::new(inner, config());
+        for _ in 0..3 {
+            assert_eq!(unwrap(cached.get_todos().await).len(), 2);
+            assert_eq!(unwrap(cached.get_todo(1).await).unwrap().id, 1);
+            assert!(unwrap(cached.get_todo(3).await).is_none());
+        }
+
+        assert_eq!(cached.stats(), CacheStats { hits: 6, misses: 3 });
+    }
+
+    #[tokio::test]
+    async fn test_writes_invalidate() {
+        let mut inner = MockTodoProvider::new();
+        inner.expect_get_todos().times(6).returning(|| Ok(vec![]));
+        inner
+            .expect_get_todo()
+            .times(6)
+            .returning(|id| Ok(Some(todo(id))));
+        inner
+            .expect_add_todo()
+            .times(1)
+            .returning(|_, _| Ok(todo(2)));
+        inner
+            .expect_update_todo()
+            .times(1)
+            .returning(|id, _, _, _| Ok(Some(todo(id))));
+        inner
+            .expect_delete_todo()
+            .times(1)
+            .returning(|id, _| Ok(Some(todo(id))));
+        inner
+            .expect_restore_todo()
+            .times(1)
+            .returning(|id, _| Ok(Some(todo(id))));
+        inner.expect_apply_batch().times(1).returning(|_, _, _| {
+            Ok(BatchOutcome {
+                committed: true,
+                results: vec![],
+            })
+        });
+
+        let cached = CachedTodoProvider::new(inner, config());
+        let read = || async {
+            unwrap(cached.get_todos().await);
+            unwrap(cached.get_todos().await);
+            unwrap(cached.get_todo(1).await);
+            unwrap(cached.get_todo(1).await);
+        };
+
+        read().await;
+        unwrap(cached.add_todo("new", "test").await);
+        read().await;
+        unwrap(cached.update_todo(1, "changed", true, "test").await);
+        read().await;
+        unwrap(cached.delete_todo(1, "test").await);
+        read().await;
+        unwrap(cached.restore_todo(1, "test").await);
+        read().await;
+        let operations = vec![BatchOperation::Delete { id: 1 }];
+        unwrap(
+            cached
+                .apply_batch(operations, BatchMode::Atomic, "test")
+                .await,
+        );
+        read().await;
+
+        assert_eq!(
+            cached.stats(),
+            CacheStats {
+                hits: 12,
+                misses: 12
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ttl() {
+        let mut inner = MockTodoProvider::new();
+        inner.expect_get_todos().times(2).returning(|| Ok(vec![]));
+
+        let cached = CachedTodoProvider::new(inner, config());
+        unwrap(cached.get_todos().await);
+        tokio::time::advance(Duration::from_secs(9)).await;
+        unwrap(cached.get_todos().await);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        unwrap(cached.get_todos().await);
+
+        assert_eq!(cached.stats(), CacheStats { hits: 1, misses: 2 });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_entries() {
+        let mut inner = MockTodoProvider::new();
+        inner
+            .expect_get_todo()
+            .times(4)
+            .returning(|id| Ok(Some(todo(id))));
+
+        let cached = CachedTodoProvider::new(inner, config());
+        for id in [1, 2, 3] {
+            unwrap(cached.get_todo(id).await);
+            tokio::time::advance(Duration::from_secs(1)).await;
+        }
+
+        // Todo 1 expired soonest, so made room for todo 3
+        unwrap(cached.get_todo(3).await);
+        unwrap(cached.get_todo(2).await);
+        unwrap(cached.get_todo(1).await);
+
+        assert_eq!(cached.stats(), CacheStats { hits: 2, misses: 4 });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_todo_is_replaced() {
+        let mut inner = MockTodoProvider::new();
+        inner
+            .expect_get_todo()
+            .times(2)
+            .returning(|id| Ok(Some(todo(id))));
+
+        let cached = CachedTodoProvider::new(inner, config());
+        unwrap(cached.get_todo(1).await);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        unwrap(cached.get_todo(1).await);
+
+        let cache = cached.cache.lock().unwrap();
+        assert_eq!(cache.todo.len(), 1);
+        assert_eq!(cache.expiries.len(), 1);
+    }
+}
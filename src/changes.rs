@@ -0,0 +1,120 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::endpoints::Todo;
+
+/// How many recent changes are kept by default for subscribers to catch up on
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug, async_graphql::Enum)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Updated => "updated",
+            ChangeKind::Deleted => "deleted",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Change {
+    /// Increases by one with every change, starting at 1
+    pub id: u64,
+    pub kind: ChangeKind,
+    /// The todo after the change, or as it was before being deleted
+    pub todo: Todo,
+}
+
+/// Broadcasts changes to todos to any number of subscribers, keeping the most recent
+/// changes so that subscribers that were disconnected can catch up.
+#[derive(Clone)]
+pub struct ChangeFeed {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    sender: broadcast::Sender<Change>,
+    capacity: usize,
+    recent: Mutex<Recent>,
+}
+
+struct Recent {
+    changes: VecDeque<Change>,
+    next_id: u64,
+}
+
+impl ChangeFeed {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            inner: Arc::new(Inner {
+                sender,
+                capacity,
+                recent: Mutex::new(Recent {
+                    changes: VecDeque::with_capacity(capacity),
+                    next_id: 1,
+                }),
+            }),
+        }
+    }
+
+    pub fn publish(&self, kind: ChangeKind, todo: Todo) -> Change {
+        // The lock is held while sending so that subscribers see changes in the same order
+        // they are recorded, with none missing between catching up and receiving
+        let mut recent = self.inner.recent.lock().unwrap();
+
+        let change = Change {
+            id: recent.next_id,
+            kind,
+            todo,
+        };
+        recent.next_id += 1;
+
+        if recent.changes.len() == self.inner.capacity {
+            recent.changes.pop_front();
+        }
+        recent.changes.push_back(change.clone());
+
+        // Sending only fails if there are no subscribers
+        let _ = self.inner.sender.send(change.clone());
+
+        change
+    }
+
+    /// Subscribes to changes published from now on. If `last_id` is given, the recent
+    /// changes after it are also returned. Changes too old to still be kept are skipped.
+    pub fn subscribe(&self, last_id: Option<u64>) -> (Vec<Change>, broadcast::Receiver<Change>) {
+        let recent = self.inner.recent.lock().unwrap();
+
+        let missed = match last_id {
+            Some(last_id) => recent
+                .changes
+                .iter()
+                .filter(|change| change.id > last_id)
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+
+        (missed, self.inner.sender.subscribe())
+    }
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
@@ -0,0 +1,97 @@
+//! A client for the todo API over HTTP, as used by the `todo` command-line tool.
+
+use anyhow::Context;
+use reqwest::{RequestBuilder, Response, Url};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::endpoints::{Todo, TodoAdd, TodoUpdate};
+
+#[derive(Clone)]
+pub struct TodoClient {
+    client: reqwest::Client,
+    base_url: Url,
+    /// Sent as a bearer token, which also identifies the client for rate limiting
+    token: Option<String>,
+}
+
+/// The body of the server's error responses
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+    request_id: Option<String>,
+}
+
+impl TodoClient {
+    pub fn new(base_url: &str, token: Option<String>) -> anyhow::Result<Self> {
+        let mut base_url =
+            Url::parse(base_url).with_context(|| format!("invalid server URL {base_url}"))?;
+        // So that paths are joined onto the URL rather than replacing its last segment
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        Ok(TodoClient {
+            client: reqwest::Client::new(),
+            base_url,
+            token,
+        })
+    }
+
+    pub async fn get_todos(&self) -> anyhow::Result<Vec<Todo>> {
+        self.send(self.client.get(self.url("todos")?)).await
+    }
+
+    pub async fn get_todo(&self, id: i64) -> anyhow::Result<Todo> {
+        self.send(self.client.get(self.url(&format!("todos/{id}"))?))
+            .await
+    }
+
+    pub async fn add_todo(&self, todo: &TodoAdd) -> anyhow::Result<Todo> {
+        self.send(self.client.post(self.url("todos")?).json(todo))
+            .await
+    }
+
+    pub async fn update_todo(&self, id: i64, todo: &TodoUpdate) -> anyhow::Result<Todo> {
+        self.send(
+            self.client
+                .put(self.url(&format!("todos/{id}"))?)
+                .json(todo),
+        )
+        .await
+    }
+
+    fn url(&self, path: &str) -> anyhow::Result<Url> {
+        Ok(self.base_url.join(path)?)
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> anyhow::Result<T> {
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response = request.send().await.context("failed to reach the server")?;
+        if !response.status().is_success() {
+            return Err(error(response).await);
+        }
+
+        response
+            .json()
+            .await
+            .context("invalid response from the server")
+    }
+}
+
+async fn error(response: Response) -> anyhow::Error {
+    let status = response.status();
+    match response.json::<ErrorBody>().await {
+        Ok(ErrorBody {
+            error,
+            request_id: Some(request_id),
+        }) => anyhow::anyhow!("{error} ({status}, request {request_id})"),
+        Ok(ErrorBody {
+            error,
+            request_id: None,
+        }) => anyhow::anyhow!("{error} ({status})"),
+        Err(_) => anyhow::anyhow!("the server responded {status}"),
+    }
+}
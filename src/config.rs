@@ -0,0 +1,23 @@
+use crate::{body_limit::BodyLimitConfig, cors::CorsConfig};
+
+/// Settings for how the router handles requests
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AppConfig {
+    pub cors: CorsConfig,
+    pub body_limits: BodyLimitConfig,
+    pub compression: CompressionConfig,
+    /// The bearer token for admin endpoints, which are disabled if this is not set
+    pub admin_token: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompressionConfig {
+    /// Responses smaller than this many bytes are not worth compressing
+    pub min_size: u16,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig { min_size: 1024 }
+    }
+}
@@ -0,0 +1,163 @@
+//! Cross-origin resource sharing, so that browser apps on other origins can call the API.
+
+use std::time::Duration;
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{endpoints::Actor, rate_limit, request_id};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorsConfig {
+    /// Origins allowed to make requests, such as `https://app.example.com`. A `*` in place
+    /// of the first label of the host, as in `https://*.example.com`, allows every
+    /// subdomain, and `*` on its own allows every origin. Cross-origin requests are not
+    /// allowed if this is empty.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<HeaderName>,
+    /// Whether to allow requests with cookies or HTTP authentication
+    pub allow_credentials: bool,
+    /// How long browsers may cache preflight responses
+    pub max_age: Option<Duration>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec![],
+            allowed_methods: vec![Method::GET, Method::POST, Method::PUT, Method::DELETE],
+            allowed_headers: vec![
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                HeaderName::from_static(Actor::HEADER),
+                HeaderName::from_static(rate_limit::API_KEY_HEADER),
+                request_id::HEADER,
+            ],
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|pattern| matches(pattern, origin))
+    }
+
+    /// Checks for settings that would let any site make requests with a user's credentials,
+    /// which are every origin being allowed along with credentials
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !(self.allow_credentials && self.allowed_origins.iter().any(|origin| origin == "*")),
+            "credentials cannot be allowed from every origin"
+        );
+        Ok(())
+    }
+
+    pub fn layer(&self) -> CorsLayer {
+        let config = self.clone();
+        let allow_origin = AllowOrigin::predicate(move |origin: &HeaderValue, _| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| config.allows_origin(origin))
+        });
+
+        let layer = CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(self.allowed_methods.clone())
+            .allow_headers(self.allowed_headers.clone())
+            .expose_headers([
+                request_id::HEADER,
+                header::RETRY_AFTER,
+                HeaderName::from_static("ratelimit-limit"),
+                HeaderName::from_static("ratelimit-remaining"),
+                HeaderName::from_static("ratelimit-reset"),
+            ])
+            .allow_credentials(self.allow_credentials);
+
+        match self.max_age {
+            Some(max_age) => layer.max_age(max_age),
+            None => layer,
+        }
+    }
+}
+
+fn matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" || pattern.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+
+    let Some((scheme, domain)) = pattern.split_once("://*.") else {
+        return false;
+    };
+    let origin = origin.to_ascii_lowercase();
+    let subdomain = origin
+        .strip_prefix(&format!("{}://", scheme.to_ascii_lowercase()))
+        .and_then(|host| host.strip_suffix(&format!(".{}", domain.to_ascii_lowercase())));
+
+    match subdomain {
+        Some(subdomain) => {
+            !subdomain.is_empty()
+                && subdomain
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut cors = CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            ..CorsConfig::default()
+        };
+        assert!(cors.validate().is_ok());
+
+        cors.allow_credentials = true;
+        assert!(cors.validate().is_err());
+
+        cors.allowed_origins = vec!["https://*.example.com".to_string()];
+        assert!(cors.validate().is_ok());
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches(
+            "https://app.example.com",
+            "https://app.example.com"
+        ));
+        assert!(!matches(
+            "https://app.example.com",
+            "http://app.example.com"
+        ));
+
+        assert!(matches("https://*.example.com", "https://app.example.com"));
+        assert!(matches("https://*.example.com", "https://a.b.example.com"));
+        assert!(!matches("https://*.example.com", "https://example.com"));
+        assert!(!matches("https://*.example.com", "https://.example.com"));
+        assert!(!matches("https://*.example.com", "https://evilexample.com"));
+        assert!(!matches(
+            "https://*.example.com",
+            "https://app.example.com.evil.com"
+        ));
+        assert!(!matches("https://*.example.com", "http://app.example.com"));
+        assert!(!matches(
+            "https://*.example.com",
+            "https://app.example.com:8443"
+        ));
+        assert!(matches(
+            "https://*.example.com:8443",
+            "https://app.example.com:8443"
+        ));
+
+        assert!(matches("*", "https://anything.test"));
+    }
+}
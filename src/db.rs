@@ -1,69 +1,574 @@
+use std::{path::Path, str::FromStr, time::Duration};
+
 use async_trait::async_trait;
-use sqlx::{query_as, Pool, Sqlite, SqlitePool};
+use sqlx::{
+    query, query_as,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+    Connection, Pool, Sqlite, SqliteConnection, SqlitePool,
+};
 
 use crate::{
-    endpoints::Todo,
-    provider::{ProviderError, TodoProvider},
+    admin,
+    changes::ChangeKind,
+    endpoints::{
+        BatchMode, BatchOperation, BatchOutcome, BatchResult, DeadLetter, Todo, TodoAction,
+        TodoRevision, TrashedTodo, Webhook,
+    },
+    provider::{BackupProvider, ProviderError, TodoProvider, WebhookProvider},
+    webhooks::{Payload, WebhookDelivery},
 };
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatabaseConfig {
+    /// How long to wait for another connection's lock before failing with `SQLITE_BUSY`
+    pub busy_timeout: Duration,
+    /// How many connections may read at once
+    pub max_readers: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            busy_timeout: Duration::from_secs(5),
+            max_readers: 4,
+        }
+    }
+}
+
+/// A pool for writing and a pool for reading, which may be the same pool.
+///
+/// In WAL mode readers see the last commit without waiting for writes in progress, while
+/// writes are serialized by SQLite regardless. The writer pool has a single connection, so
+/// that writers queue for it rather than contending for the lock and failing when their
+/// busy timeout runs out.
 #[derive(Clone)]
-pub struct SqliteTodoProvider {
-    pool: SqlitePool,
+pub struct SqlitePools {
+    pub writer: SqlitePool,
+    pub reader: SqlitePool,
 }
 
-impl From<&Pool<Sqlite>> for SqliteTodoProvider {
+impl SqlitePools {
+    /// Opens the database at `url`, creating it if it does not exist
+    pub async fn connect(url: &str, config: &DatabaseConfig) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(config.busy_timeout)
+            .foreign_keys(true)
+            .create_if_missing(true);
+
+        // Connected first, so that the database is created and in WAL mode before readers
+        // open it
+        let writer = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options.clone())
+            .await?;
+        // Not `query_only`, which would also stop backups with `VACUUM INTO`
+        let reader = SqlitePoolOptions::new()
+            .max_connections(config.max_readers)
+            .connect_with(options)
+            .await?;
+
+        Ok(SqlitePools { writer, reader })
+    }
+}
+
+impl From<&Pool<Sqlite>> for SqlitePools {
     fn from(value: &Pool<Sqlite>) -> Self {
+        SqlitePools {
+            writer: value.clone(),
+            reader: value.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteTodoProvider {
+    writer: SqlitePool,
+    reader: SqlitePool,
+}
+
+impl From<&SqlitePools> for SqliteTodoProvider {
+    fn from(value: &SqlitePools) -> Self {
         SqliteTodoProvider {
-            pool: value.clone(),
+            writer: value.writer.clone(),
+            reader: value.reader.clone(),
         }
     }
 }
 
+impl From<&Pool<Sqlite>> for SqliteTodoProvider {
+    fn from(value: &Pool<Sqlite>) -> Self {
+        Self::from(&SqlitePools::from(value))
+    }
+}
+
 #[async_trait]
 impl TodoProvider for SqliteTodoProvider {
     async fn get_todos(&self) -> Result<Vec<Todo>, ProviderError> {
-        let todos = query_as!(Todo, "select * from todos")
-            .fetch_all(&self.pool)
-            .await?;
+        let todos = query_as!(
+            Todo,
+            "select id, description, done from todos where deleted_at is null"
+        )
+        .fetch_all(&self.reader)
+        .await?;
         Ok(todos)
     }
 
     async fn get_todo(&self, id: i64) -> Result<Option<Todo>, ProviderError> {
-        let todo = query_as!(Todo, "select * from todos where id=?1", id)
-            .fetch_optional(&self.pool)
-            .await?;
-        Ok(todo)
-    }
-
-    async fn add_todo(&self, description: &str) -> Result<Todo, ProviderError> {
         let todo = query_as!(
             Todo,
-            "insert into todos (description) values (?1) returning *",
-            description
+            "select id, description, done from todos where id=?1 and deleted_at is null",
+            id
         )
-        .fetch_one(&self.pool)
+        .fetch_optional(&self.reader)
         .await?;
         Ok(todo)
     }
 
+    async fn add_todo(&self, description: &str, actor: &str) -> Result<Todo, ProviderError> {
+        let mut tx = self.writer.begin().await?;
+        let todo = add_todo(&mut tx, description, false, actor).await?;
+        tx.commit().await?;
+        Ok(todo)
+    }
+
     async fn update_todo(
         &self,
         id: i64,
         description: &str,
         done: bool,
-    ) -> Result<Todo, ProviderError> {
+        actor: &str,
+    ) -> Result<Option<Todo>, ProviderError> {
+        let mut tx = self.writer.begin().await?;
+        let todo = update_todo(&mut tx, id, description, done, actor).await?;
+        tx.commit().await?;
+        Ok(todo)
+    }
+
+    async fn delete_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError> {
+        let mut tx = self.writer.begin().await?;
+        let todo = delete_todo(&mut tx, id, actor).await?;
+        tx.commit().await?;
+        Ok(todo)
+    }
+
+    async fn get_trash(&self) -> Result<Vec<TrashedTodo>, ProviderError> {
+        let todos = query_as!(
+            TrashedTodo,
+            "select id, description, done, deleted_at as \"deleted_at!\" from todos
+            where deleted_at is not null
+            order by deleted_at desc, id"
+        )
+        .fetch_all(&self.reader)
+        .await?;
+        Ok(todos)
+    }
+
+    async fn restore_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError> {
+        let mut tx = self.writer.begin().await?;
+
+        query!(
+            "insert into todo_events (todo_id, revision, action, new_description, new_done, actor)
+            select id, (select coalesce(max(revision), 0) + 1 from todo_events where todo_id=?1),
+                ?2, description, done, ?3
+            from todos where id=?1 and deleted_at is not null",
+            id,
+            TodoAction::Restored,
+            actor
+        )
+        .execute(&mut *tx)
+        .await?;
+
         let todo = query_as!(
             Todo,
-            // Work-around for bug where id gets returned as nullable
-            "update todos set description=?1, done=?2 where id=?3
+            "update todos set deleted_at=null where id=?1 and deleted_at is not null
             returning id as \"id!\", description, done",
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(todo) = &todo {
+            // To anyone watching for changes, a restored todo is a new one
+            enqueue_deliveries(&mut tx, ChangeKind::Created, todo).await?;
+        }
+
+        tx.commit().await?;
+        Ok(todo)
+    }
+
+    async fn purge_trash(&self, retention: Duration, actor: &str) -> Result<u64, ProviderError> {
+        let retention = i64::try_from(retention.as_secs()).unwrap_or(i64::MAX);
+        let mut tx = self.writer.begin().await?;
+
+        query!(
+            "insert into todo_events (todo_id, revision, action, old_description, old_done, actor)
+            select id, (select coalesce(max(revision), 0) + 1 from todo_events where todo_id=todos.id),
+                ?2, description, done, ?3
+            from todos where deleted_at is not null and deleted_at <= unixepoch() - ?1",
+            retention,
+            TodoAction::Purged,
+            actor
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let result = query!(
+            "delete from todos where deleted_at is not null and deleted_at <= unixepoch() - ?1",
+            retention
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn get_history(&self, id: i64) -> Result<Vec<TodoRevision>, ProviderError> {
+        let revisions = query_as!(
+            TodoRevision,
+            "select revision, action as \"action: TodoAction\",
+                old_description, old_done, new_description, new_done, actor, created_at
+            from todo_events where todo_id=?1
+            order by revision",
+            id
+        )
+        .fetch_all(&self.reader)
+        .await?;
+        Ok(revisions)
+    }
+
+    async fn apply_batch(
+        &self,
+        operations: Vec<BatchOperation>,
+        mode: BatchMode,
+        actor: &str,
+    ) -> Result<BatchOutcome, ProviderError> {
+        let mut tx = self.writer.begin().await?;
+        let mut results = Vec::with_capacity(operations.len());
+        let mut failed = false;
+
+        for operation in operations {
+            if failed {
+                results.push(BatchResult::Skipped);
+                continue;
+            }
+
+            // Each operation gets its own savepoint so that a failed operation leaves no
+            // partial changes behind in best-effort mode
+            let mut savepoint = tx.begin().await?;
+            let result = match apply_operation(&mut savepoint, operation, actor).await {
+                Ok(Some(todo)) => BatchResult::Ok { todo },
+                Ok(None) => BatchResult::NotFound,
+                Err(err) => BatchResult::Failed {
+                    error: err.to_string(),
+                },
+            };
+
+            if let BatchResult::Ok { .. } = result {
+                savepoint.commit().await?;
+            } else {
+                savepoint.rollback().await?;
+                failed = mode == BatchMode::Atomic;
+            }
+
+            results.push(result);
+        }
+
+        if failed {
+            tx.rollback().await?;
+            for result in &mut results {
+                if let BatchResult::Ok { .. } = result {
+                    *result = BatchResult::RolledBack;
+                }
+            }
+        } else {
+            tx.commit().await?;
+        }
+
+        Ok(BatchOutcome {
+            committed: !failed,
+            results,
+        })
+    }
+}
+
+// Writes record their change in `todo_events` and queue webhook deliveries for it on the
+// same connection, which callers run within a transaction, so that a change is never made
+// without its deliveries or the other way around. Where the old values are needed, the
+// event is inserted before the write so that reading the old values and taking the write
+// lock happen in one statement.
+
+async fn add_todo(
+    conn: &mut SqliteConnection,
+    description: &str,
+    done: bool,
+    actor: &str,
+) -> Result<Todo, sqlx::Error> {
+    let todo = query_as!(
+        Todo,
+        "insert into todos (description, done) values (?1, ?2)
+        returning id, description, done",
+        description,
+        done
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    query!(
+        "insert into todo_events (todo_id, revision, action, new_description, new_done, actor)
+        values (?1, 1, ?2, ?3, ?4, ?5)",
+        todo.id,
+        TodoAction::Created,
+        todo.description,
+        todo.done,
+        actor
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    enqueue_deliveries(conn, ChangeKind::Created, &todo).await?;
+    Ok(todo)
+}
+
+async fn update_todo(
+    conn: &mut SqliteConnection,
+    id: i64,
+    description: &str,
+    done: bool,
+    actor: &str,
+) -> Result<Option<Todo>, sqlx::Error> {
+    query!(
+        "insert into todo_events
+            (todo_id, revision, action, old_description, old_done, new_description, new_done, actor)
+        select id, (select coalesce(max(revision), 0) + 1 from todo_events where todo_id=?1),
+            ?2, description, done, ?3, ?4, ?5
+        from todos where id=?1 and deleted_at is null",
+        id,
+        TodoAction::Updated,
+        description,
+        done,
+        actor
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let todo = query_as!(
+        Todo,
+        // Work-around for bug where id gets returned as nullable
+        "update todos set description=?1, done=?2 where id=?3 and deleted_at is null
+        returning id as \"id!\", description, done",
+        description,
+        done,
+        id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(todo) = &todo {
+        enqueue_deliveries(conn, ChangeKind::Updated, todo).await?;
+    }
+    Ok(todo)
+}
+
+async fn delete_todo(
+    conn: &mut SqliteConnection,
+    id: i64,
+    actor: &str,
+) -> Result<Option<Todo>, sqlx::Error> {
+    query!(
+        "insert into todo_events (todo_id, revision, action, old_description, old_done, actor)
+        select id, (select coalesce(max(revision), 0) + 1 from todo_events where todo_id=?1),
+            ?2, description, done, ?3
+        from todos where id=?1 and deleted_at is null",
+        id,
+        TodoAction::Deleted,
+        actor
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let todo = query_as!(
+        Todo,
+        "update todos set deleted_at=unixepoch() where id=?1 and deleted_at is null
+        returning id as \"id!\", description, done",
+        id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(todo) = &todo {
+        enqueue_deliveries(conn, ChangeKind::Deleted, todo).await?;
+    }
+    Ok(todo)
+}
+
+/// Queues a delivery of the change to every webhook
+async fn enqueue_deliveries(
+    conn: &mut SqliteConnection,
+    kind: ChangeKind,
+    todo: &Todo,
+) -> Result<(), sqlx::Error> {
+    let payload = Payload { event: kind, todo };
+    let payload = serde_json::to_string(&payload).expect("payloads always serialize");
+    query!(
+        "insert into webhook_deliveries (webhook_id, payload) select id, ?1 from webhooks",
+        payload
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+async fn apply_operation(
+    conn: &mut SqliteConnection,
+    operation: BatchOperation,
+    actor: &str,
+) -> Result<Option<Todo>, sqlx::Error> {
+    match operation {
+        BatchOperation::Create { description, done } => {
+            add_todo(conn, &description, done, actor).await.map(Some)
+        }
+        BatchOperation::Update {
+            id,
             description,
             done,
-            id
+        } => update_todo(conn, id, &description, done, actor).await,
+        BatchOperation::Delete { id } => delete_todo(conn, id, actor).await,
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteWebhookProvider {
+    pool: SqlitePool,
+}
+
+impl From<&Pool<Sqlite>> for SqliteWebhookProvider {
+    fn from(value: &Pool<Sqlite>) -> Self {
+        SqliteWebhookProvider {
+            pool: value.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl WebhookProvider for SqliteWebhookProvider {
+    async fn get_webhooks(&self) -> Result<Vec<Webhook>, ProviderError> {
+        let webhooks = query_as!(
+            Webhook,
+            "select id, url, secret, created_at from webhooks order by id"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(webhooks)
+    }
+
+    async fn add_webhook(&self, url: &str, secret: &str) -> Result<Webhook, ProviderError> {
+        let webhook = query_as!(
+            Webhook,
+            "insert into webhooks (url, secret) values (?1, ?2)
+            returning id, url, secret, created_at",
+            url,
+            secret
         )
         .fetch_one(&self.pool)
         .await?;
-        Ok(todo)
+        Ok(webhook)
+    }
+
+    async fn delete_webhook(&self, id: i64) -> Result<bool, ProviderError> {
+        let mut tx = self.pool.begin().await?;
+
+        query!("delete from webhook_deliveries where webhook_id=?1", id)
+            .execute(&mut *tx)
+            .await?;
+        let result = query!("delete from webhooks where id=?1", id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_due_deliveries(&self, limit: i64) -> Result<Vec<WebhookDelivery>, ProviderError> {
+        let deliveries = query_as!(
+            WebhookDelivery,
+            "select d.id, d.webhook_id, w.url, w.secret, d.payload, d.attempts
+            from webhook_deliveries d join webhooks w on w.id = d.webhook_id
+            where d.status = 'pending' and d.next_attempt_at <= unixepoch()
+            order by d.next_attempt_at, d.id
+            limit ?1",
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(deliveries)
+    }
+
+    async fn mark_delivered(&self, id: i64) -> Result<(), ProviderError> {
+        query!("delete from webhook_deliveries where id=?1", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &self,
+        id: i64,
+        error: &str,
+        retry_in: Option<Duration>,
+    ) -> Result<(), ProviderError> {
+        match retry_in {
+            Some(retry_in) => {
+                let retry_in = i64::try_from(retry_in.as_secs()).unwrap_or(i64::MAX);
+                query!(
+                    "update webhook_deliveries
+                    set attempts = attempts + 1, last_error = ?2,
+                        next_attempt_at = unixepoch() + ?3
+                    where id=?1",
+                    id,
+                    error,
+                    retry_in
+                )
+                .execute(&self.pool)
+                .await?;
+            }
+            None => {
+                query!(
+                    "update webhook_deliveries
+                    set attempts = attempts + 1, last_error = ?2, status = 'dead'
+                    where id=?1",
+                    id,
+                    error
+                )
+                .execute(&self.pool)
+                .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_dead_letters(&self) -> Result<Vec<DeadLetter>, ProviderError> {
+        let dead_letters = query_as!(
+            DeadLetter,
+            "select d.id, d.webhook_id, w.url, d.payload, d.attempts, d.last_error, d.created_at
+            from webhook_deliveries d join webhooks w on w.id = d.webhook_id
+            where d.status = 'dead'
+            order by d.id"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(dead_letters)
+    }
+
+    async fn retry_dead_letter(&self, id: i64) -> Result<bool, ProviderError> {
+        let result = query!(
+            "update webhook_deliveries
+            set status = 'pending', attempts = 0, next_attempt_at = unixepoch()
+            where id=?1 and status = 'dead'",
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
     }
 }
 
@@ -72,3 +577,23 @@ impl From<sqlx::Error> for ProviderError {
         ProviderError(value.into())
     }
 }
+
+#[derive(Clone)]
+pub struct SqliteBackupProvider {
+    pool: SqlitePool,
+}
+
+impl From<&Pool<Sqlite>> for SqliteBackupProvider {
+    fn from(value: &Pool<Sqlite>) -> Self {
+        SqliteBackupProvider {
+            pool: value.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl BackupProvider for SqliteBackupProvider {
+    async fn backup(&self, path: &Path) -> Result<(), ProviderError> {
+        admin::backup(&self.pool, path).await.map_err(ProviderError)
+    }
+}
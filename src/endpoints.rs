@@ -1,19 +1,51 @@
+use std::{
+    convert::Infallible,
+    time::{Duration, SystemTime},
+};
+
+use async_graphql::http::GraphiQLSource;
+use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use async_trait::async_trait;
 use axum::{
-    extract::{Path, State},
+    body::{Body, Bytes},
+    extract::{ws::WebSocketUpgrade, Extension, FromRequestParts, Path, Query, State},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
     Json,
 };
-use http::StatusCode;
+use futures_util::{stream, Stream, StreamExt};
+use http::{header, request::Parts, HeaderMap, StatusCode};
 use serde::{Deserialize, Serialize};
+use tokio_util::io::ReaderStream;
 
 use crate::{
     app::{AppError, AppState},
-    provider::TodoProvider,
+    changes::ChangeKind,
+    formats::{self, ImportRow, RowError},
+    graphql::TodoSchema,
+    provider::{BackupProvider, TodoProvider, WebhookProvider},
+    websocket,
 };
 
-pub async fn get_todos<A: AppState>(State(state): State<A>) -> Result<Json<Vec<Todo>>, AppError> {
+/// Returns every todo as JSON, or as todo.txt if the client prefers `text/plain`.
+pub async fn get_todos<A: AppState>(
+    State(state): State<A>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
     let todos = state.provider().get_todos().await?;
 
-    Ok(Json(todos))
+    if prefers_todo_txt(&headers) {
+        let text = formats::todotxt::write_todos(&todos);
+        return Ok((
+            [(header::CONTENT_TYPE, mime::TEXT_PLAIN_UTF_8.as_ref())],
+            text,
+        )
+            .into_response());
+    }
+
+    Ok(Json(todos).into_response())
 }
 
 pub async fn get_todo<A: AppState>(
@@ -32,10 +64,12 @@ pub async fn get_todo<A: AppState>(
 
 pub async fn add_todo<A: AppState>(
     State(state): State<A>,
+    actor: Actor,
     Json(todo): Json<TodoAdd>,
 ) -> Result<(StatusCode, Json<Todo>), AppError> {
     let TodoAdd { description } = todo;
-    let todo = state.provider().add_todo(&description).await?;
+    let todo = state.provider().add_todo(&description, &actor.0).await?;
+    state.changes().publish(ChangeKind::Created, todo.clone());
 
     Ok((StatusCode::CREATED, Json(todo)))
 }
@@ -43,28 +77,707 @@ pub async fn add_todo<A: AppState>(
 pub async fn update_todo<A: AppState>(
     State(state): State<A>,
     Path(id): Path<i64>,
+    actor: Actor,
     Json(todo): Json<TodoUpdate>,
 ) -> Result<Json<Todo>, AppError> {
     let TodoUpdate { description, done } = todo;
-    let todo = state.provider().update_todo(id, &description, done).await?;
+    let todo = state
+        .provider()
+        .update_todo(id, &description, done, &actor.0)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    state.changes().publish(ChangeKind::Updated, todo.clone());
 
     Ok(Json(todo))
 }
 
-#[derive(Serialize, Clone)]
+pub async fn delete_todo<A: AppState>(
+    State(state): State<A>,
+    Path(id): Path<i64>,
+    actor: Actor,
+) -> Result<StatusCode, AppError> {
+    match state.provider().delete_todo(id, &actor.0).await? {
+        Some(todo) => {
+            state.changes().publish(ChangeKind::Deleted, todo);
+            Ok(StatusCode::NO_CONTENT)
+        }
+        None => Err(AppError::NotFound),
+    }
+}
+
+pub async fn get_trash<A: AppState>(
+    State(state): State<A>,
+) -> Result<Json<Vec<TrashedTodo>>, AppError> {
+    let todos = state.provider().get_trash().await?;
+
+    Ok(Json(todos))
+}
+
+pub async fn restore_todo<A: AppState>(
+    State(state): State<A>,
+    Path(id): Path<i64>,
+    actor: Actor,
+) -> Result<Json<Todo>, AppError> {
+    let todo = state.provider().restore_todo(id, &actor.0).await?;
+
+    let todo = match todo {
+        Some(todo) => todo,
+        None => return Err(AppError::NotFound),
+    };
+
+    // To anyone watching for changes, a restored todo is a new one
+    state.changes().publish(ChangeKind::Created, todo.clone());
+
+    Ok(Json(todo))
+}
+
+pub async fn purge_trash<A: AppState>(
+    State(state): State<A>,
+    actor: Actor,
+) -> Result<Json<TrashPurge>, AppError> {
+    let purged = state
+        .provider()
+        .purge_trash(Duration::ZERO, &actor.0)
+        .await?;
+
+    Ok(Json(TrashPurge { purged }))
+}
+
+pub async fn get_history<A: AppState>(
+    State(state): State<A>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<TodoRevision>>, AppError> {
+    let revisions = state.provider().get_history(id).await?;
+
+    if revisions.is_empty() {
+        return Err(AppError::NotFound);
+    }
+
+    Ok(Json(revisions))
+}
+
+/// Restores the description and done state a todo had as of an earlier revision. This is
+/// a regular update, so it is recorded as a new revision rather than rewriting history.
+pub async fn restore_revision<A: AppState>(
+    State(state): State<A>,
+    Path((id, revision)): Path<(i64, i64)>,
+    actor: Actor,
+) -> Result<Json<Todo>, AppError> {
+    let revisions = state.provider().get_history(id).await?;
+
+    let (description, done) = match revisions.into_iter().find(|r| r.revision == revision) {
+        Some(revision) => revision.state(),
+        None => return Err(AppError::NotFound),
+    };
+
+    let todo = state
+        .provider()
+        .update_todo(id, &description, done, &actor.0)
+        .await?
+        .ok_or(AppError::NotFound)?;
+    state.changes().publish(ChangeKind::Updated, todo.clone());
+
+    Ok(Json(todo))
+}
+
+pub async fn apply_batch<A: AppState>(
+    State(state): State<A>,
+    actor: Actor,
+    Json(batch): Json<TodoBatch>,
+) -> Result<(StatusCode, Json<BatchOutcome>), AppError> {
+    let TodoBatch { mode, operations } = batch;
+    let kinds: Vec<_> = operations
+        .iter()
+        .map(|operation| match operation {
+            BatchOperation::Create { .. } => ChangeKind::Created,
+            BatchOperation::Update { .. } => ChangeKind::Updated,
+            BatchOperation::Delete { .. } => ChangeKind::Deleted,
+        })
+        .collect();
+
+    let outcome = state
+        .provider()
+        .apply_batch(operations, mode, &actor.0)
+        .await?;
+
+    let status = if outcome.committed {
+        for (kind, result) in kinds.into_iter().zip(&outcome.results) {
+            if let BatchResult::Ok { todo } = result {
+                state.changes().publish(kind, todo.clone());
+            }
+        }
+        StatusCode::OK
+    } else {
+        StatusCode::CONFLICT
+    };
+
+    Ok((status, Json(outcome)))
+}
+
+/// Streams changes to todos as server-sent events named after the kind of change, with the
+/// todo as data. Clients reconnecting with `Last-Event-ID` first receive the changes they
+/// missed, as far as they are still kept.
+pub async fn todo_events<A: AppState>(
+    State(state): State<A>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok());
+
+    let (missed, receiver) = state.changes().subscribe(last_id);
+
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        // A subscriber that falls too far behind is disconnected rather than silently
+        // skipping changes, so that it reconnects and catches up from its last event id
+        let change = receiver.recv().await.ok()?;
+        Some((change, receiver))
+    });
+
+    let events = stream::iter(missed).chain(live).map(|change| {
+        let event = Event::default()
+            .id(change.id.to_string())
+            .event(change.kind.as_str())
+            .json_data(&change.todo)
+            .expect("todos always serialize");
+        Ok(event)
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Upgrades to a WebSocket speaking the protocol described in [`websocket`].
+pub async fn websocket<A: AppState>(
+    State(state): State<A>,
+    actor: Actor,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| websocket::serve(socket, state, actor.0))
+}
+
+/// Executes a GraphQL query or mutation, as the actor named in the headers.
+pub async fn graphql<A: AppState>(
+    Extension(schema): Extension<TodoSchema<A>>,
+    actor: Actor,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    schema
+        .execute(request.into_inner().data(actor))
+        .await
+        .into()
+}
+
+pub async fn graphiql() -> Html<String> {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/graphql/ws")
+            .finish(),
+    )
+}
+
+/// Returns every todo as CSV, one record per todo after a header row. The todos are all
+/// loaded before the response is sent, but the rows are written as the body is streamed.
+pub async fn export_csv<A: AppState>(
+    State(state): State<A>,
+) -> Result<impl IntoResponse, AppError> {
+    let todos = state.provider().get_todos().await?;
+
+    let header = Bytes::from_static(formats::csv::HEADER.as_bytes());
+    let rows = todos
+        .into_iter()
+        .map(|todo| Bytes::from(formats::csv::write_row(&todo)));
+    let chunks = std::iter::once(header).chain(rows).map(Ok::<_, Infallible>);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime::TEXT_CSV_UTF_8.as_ref()),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"todos.csv\"",
+            ),
+        ],
+        Body::from_stream(stream::iter(chunks)),
+    ))
+}
+
+/// Renders every todo as an iCalendar `VTODO`.
+pub async fn export_ical<A: AppState>(
+    State(state): State<A>,
+) -> Result<impl IntoResponse, AppError> {
+    let todos = state.provider().get_todos().await?;
+
+    let calendar = formats::ical::write_calendar(&todos, SystemTime::now());
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        calendar,
+    ))
+}
+
+/// Adds every todo in an uploaded file, which may be CSV, iCalendar or todo.txt, in one
+/// batch so that either every todo is added or none are. If any row cannot be read, nothing
+/// is imported and the errors are reported instead. In a dry run, the file is only checked.
+pub async fn import_todos<A: AppState>(
+    State(state): State<A>,
+    actor: Actor,
+    Query(options): Query<ImportOptions>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<(StatusCode, Json<ImportReport>), AppError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<mime::Mime>().ok())
+        .ok_or(AppError::UnsupportedMediaType)?;
+
+    let rows = match content_type.essence_str() {
+        "text/csv" => {
+            let defaults = formats::csv::ColumnMapping::default();
+            let mapping = formats::csv::ColumnMapping {
+                description: options.description_column.unwrap_or(defaults.description),
+                done: options.done_column,
+            };
+            formats::csv::parse(&body, &mapping).map_err(AppError::BadRequest)?
+        }
+        "text/calendar" => {
+            let body = std::str::from_utf8(&body)
+                .map_err(|_| AppError::BadRequest("calendar is not valid UTF-8".to_string()))?;
+            formats::ical::parse(body).map_err(AppError::BadRequest)?
+        }
+        "text/plain" => {
+            let body = std::str::from_utf8(&body)
+                .map_err(|_| AppError::BadRequest("todo.txt is not valid UTF-8".to_string()))?;
+            formats::todotxt::parse(body)
+        }
+        _ => return Err(AppError::UnsupportedMediaType),
+    };
+
+    let mut valid = Vec::new();
+    let mut errors = Vec::new();
+    for row in rows {
+        match row {
+            Ok(row) => valid.push(row),
+            Err(err) => errors.push(err),
+        }
+    }
+
+    if !errors.is_empty() {
+        let report = ImportReport {
+            dry_run: options.dry_run,
+            imported: 0,
+            errors,
+        };
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(report)));
+    }
+
+    if options.dry_run {
+        let report = ImportReport {
+            dry_run: true,
+            imported: valid.len(),
+            errors,
+        };
+        return Ok((StatusCode::OK, Json(report)));
+    }
+
+    let operations = valid
+        .iter()
+        .map(|ImportRow { description, done }| BatchOperation::Create {
+            description: description.clone(),
+            done: *done,
+        })
+        .collect();
+    let outcome = state
+        .provider()
+        .apply_batch(operations, BatchMode::Atomic, &actor.0)
+        .await?;
+    if !outcome.committed {
+        let error = outcome.results.into_iter().find_map(|result| match result {
+            BatchResult::Failed { error } => Some(error),
+            _ => None,
+        });
+        return Err(AppError::InternalServerError(anyhow::anyhow!(
+            "import failed: {}",
+            error.as_deref().unwrap_or("unknown error")
+        )));
+    }
+    for result in outcome.results {
+        if let BatchResult::Ok { todo } = result {
+            state.changes().publish(ChangeKind::Created, todo);
+        }
+    }
+
+    let report = ImportReport {
+        dry_run: false,
+        imported: valid.len(),
+        errors,
+    };
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+pub async fn get_webhooks<A: AppState>(
+    _: Admin,
+    State(state): State<A>,
+) -> Result<Json<Vec<Webhook>>, AppError> {
+    let webhooks = state.webhooks().get_webhooks().await?;
+
+    Ok(Json(webhooks))
+}
+
+pub async fn add_webhook<A: AppState>(
+    _: Admin,
+    State(state): State<A>,
+    Json(webhook): Json<WebhookAdd>,
+) -> Result<(StatusCode, Json<Webhook>), AppError> {
+    let WebhookAdd { url, secret } = webhook;
+
+    match reqwest::Url::parse(&url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+        _ => {
+            return Err(AppError::BadRequest(
+                "url must be an http or https URL".into(),
+            ))
+        }
+    }
+    if secret.is_empty() {
+        return Err(AppError::BadRequest("secret must not be empty".into()));
+    }
+
+    let webhook = state.webhooks().add_webhook(&url, &secret).await?;
+
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+pub async fn delete_webhook<A: AppState>(
+    _: Admin,
+    State(state): State<A>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    if state.webhooks().delete_webhook(id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound)
+    }
+}
+
+pub async fn get_dead_letters<A: AppState>(
+    _: Admin,
+    State(state): State<A>,
+) -> Result<Json<Vec<DeadLetter>>, AppError> {
+    let dead_letters = state.webhooks().get_dead_letters().await?;
+
+    Ok(Json(dead_letters))
+}
+
+pub async fn retry_dead_letter<A: AppState>(
+    _: Admin,
+    State(state): State<A>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    if state.webhooks().retry_dead_letter(id).await? {
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        Err(AppError::NotFound)
+    }
+}
+
+/// Streams a consistent snapshot of the database, taken while it is in use
+pub async fn backup<A: AppState>(_: Admin, State(state): State<A>) -> Result<Response, AppError> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("todos.db");
+    state.backups().backup(&path).await?;
+
+    // The file is read through the open handle once the directory is removed
+    let file = tokio::fs::File::open(&path).await?;
+    drop(dir);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/vnd.sqlite3"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"todos.db\"",
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response())
+}
+
+/// Whether the `Accept` header ranks `text/plain` above `application/json`. More specific
+/// media ranges take precedence over wildcards, and JSON wins ties.
+fn prefers_todo_txt(headers: &HeaderMap) -> bool {
+    // The quality and specificity of the most specific range matching each type
+    let mut json = (0.0, 0);
+    let mut text = (0.0, 0);
+
+    let ranges = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|range| range.trim().parse::<mime::Mime>().ok());
+
+    for range in ranges {
+        let quality = range
+            .get_param("q")
+            .and_then(|q| q.as_str().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        for (target, matched) in [
+            (&mut json, mime::APPLICATION_JSON),
+            (&mut text, mime::TEXT_PLAIN),
+        ] {
+            let specificity = match (range.type_(), range.subtype()) {
+                (mime::STAR, mime::STAR) => 1,
+                (type_, mime::STAR) if type_ == matched.type_() => 2,
+                (type_, subtype) if type_ == matched.type_() && subtype == matched.subtype() => 3,
+                _ => continue,
+            };
+            if specificity > target.1 {
+                *target = (quality, specificity);
+            }
+        }
+    }
+
+    text.0 > json.0
+}
+
+#[derive(Serialize, Deserialize, Clone, async_graphql::SimpleObject)]
 pub struct Todo {
     pub id: i64,
     pub description: String,
     pub done: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TodoAdd {
     pub description: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TodoUpdate {
     pub description: String,
     pub done: bool,
 }
+
+#[derive(Serialize, Clone)]
+pub struct TrashedTodo {
+    pub id: i64,
+    pub description: String,
+    pub done: bool,
+    /// Unix timestamp, in seconds, of when the todo was moved to the trash
+    pub deleted_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct TrashPurge {
+    pub purged: u64,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum TodoAction {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    Purged,
+}
+
+/// A single recorded change to a todo. The old values are absent for changes that bring a
+/// todo into existence and the new values are absent for changes that remove it.
+#[derive(Serialize, Clone)]
+pub struct TodoRevision {
+    pub revision: i64,
+    pub action: TodoAction,
+    pub old_description: Option<String>,
+    pub old_done: Option<bool>,
+    pub new_description: Option<String>,
+    pub new_done: Option<bool>,
+    pub actor: String,
+    /// Unix timestamp, in seconds, of when the change was made
+    pub created_at: i64,
+}
+
+impl TodoRevision {
+    /// The description and done state of the todo as of this revision. For revisions that
+    /// removed the todo, this is the state it had when it was removed.
+    pub fn state(self) -> (String, bool) {
+        match (self.new_description, self.new_done) {
+            (Some(description), Some(done)) => (description, done),
+            _ => (
+                self.old_description.unwrap_or_default(),
+                self.old_done.unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TodoBatch {
+    #[serde(default)]
+    pub mode: BatchMode,
+    pub operations: Vec<BatchOperation>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMode {
+    /// Either every operation is applied or, if any fails, none are
+    #[default]
+    Atomic,
+    /// Operations that succeed are applied even if others fail
+    BestEffort,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOperation {
+    Create {
+        description: String,
+        #[serde(default)]
+        done: bool,
+    },
+    Update {
+        id: i64,
+        description: String,
+        done: bool,
+    },
+    Delete {
+        id: i64,
+    },
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchResult {
+    /// The operation was applied, resulting in `todo`
+    Ok {
+        todo: Todo,
+    },
+    /// The operation targets a todo that does not exist
+    NotFound,
+    Failed {
+        error: String,
+    },
+    /// The operation succeeded, but was undone because another operation failed
+    RolledBack,
+    /// The operation was not attempted because an earlier operation failed
+    Skipped,
+}
+
+#[derive(Serialize, Clone)]
+pub struct BatchOutcome {
+    /// Whether any changes were kept. Always true in best-effort mode.
+    pub committed: bool,
+    /// The result of each operation, in the order they were given
+    pub results: Vec<BatchResult>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ImportOptions {
+    #[serde(default)]
+    pub dry_run: bool,
+    /// The CSV column holding descriptions, `description` if not given
+    pub description_column: Option<String>,
+    /// The CSV column holding done states. If not given, a `done` column is used if there
+    /// is one; otherwise todos are imported as not done.
+    pub done_column: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// How many todos were imported, or would have been in a dry run
+    pub imported: usize,
+    pub errors: Vec<RowError>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    /// Used to sign deliveries, so it is never sent back
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub created_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct WebhookAdd {
+    pub url: String,
+    pub secret: String,
+}
+
+/// A delivery that ran out of attempts
+#[derive(Serialize, Clone)]
+pub struct DeadLetter {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub url: String,
+    pub payload: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+}
+
+/// Who is making a change, taken from the `X-Actor` request header
+pub struct Actor(pub String);
+
+impl Actor {
+    pub const HEADER: &'static str = "x-actor";
+    pub const ANONYMOUS: &'static str = "anonymous";
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Actor {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let actor = parts
+            .headers
+            .get(Actor::HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .unwrap_or(Actor::ANONYMOUS);
+
+        Ok(Actor(actor.to_string()))
+    }
+}
+
+/// Proof that the request has the admin token as a bearer token
+pub struct Admin;
+
+#[async_trait]
+impl<A: AppState> FromRequestParts<A> for Admin {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &A) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+
+        match (&state.config().admin_token, token) {
+            (Some(admin_token), Some(token)) if constant_time_eq(admin_token, token) => Ok(Admin),
+            _ => Err(AppError::Unauthorized),
+        }
+    }
+}
+
+/// Compares without returning early, so that the time taken reveals nothing about `secret`
+/// but its length
+pub(crate) fn constant_time_eq(secret: &str, guess: &str) -> bool {
+    secret.len() == guess.len()
+        && secret
+            .bytes()
+            .zip(guess.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
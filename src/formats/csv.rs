@@ -0,0 +1,107 @@
+use ::csv::{ReaderBuilder, StringRecord, Trim, WriterBuilder};
+
+use crate::endpoints::Todo;
+
+use super::{ImportRow, RowError};
+
+/// The header row written before exported todos
+pub const HEADER: &str = "id,description,done\n";
+
+/// Which columns of an imported file hold which fields. Column names are matched
+/// case-insensitively.
+#[derive(Debug, Clone)]
+pub struct ColumnMapping {
+    pub description: String,
+    /// The done column may be left out of a file entirely unless it was explicitly mapped
+    pub done: Option<String>,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self {
+            description: "description".to_string(),
+            done: None,
+        }
+    }
+}
+
+/// Writes a todo as a single CSV record, including the trailing newline.
+pub fn write_row(todo: &Todo) -> Vec<u8> {
+    let mut writer = WriterBuilder::new().from_writer(vec![]);
+    let done = if todo.done { "true" } else { "false" };
+    writer
+        .write_record([todo.id.to_string().as_str(), &todo.description, done])
+        .expect("writing to a Vec cannot fail");
+    writer.into_inner().expect("writing to a Vec cannot fail")
+}
+
+/// Reads todos from CSV with a header row. Fails as a whole if the header does not contain
+/// the mapped columns; otherwise every record yields either a row or the reason it could not
+/// be read.
+pub fn parse(
+    input: &[u8],
+    mapping: &ColumnMapping,
+) -> Result<Vec<Result<ImportRow, RowError>>, String> {
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(input);
+
+    let headers = reader.headers().map_err(|err| err.to_string())?.clone();
+    let description_index = column_index(&headers, &mapping.description)
+        .ok_or_else(|| format!("missing column \"{}\"", mapping.description))?;
+    let done_index = match &mapping.done {
+        Some(done) => {
+            Some(column_index(&headers, done).ok_or_else(|| format!("missing column \"{done}\""))?)
+        }
+        None => column_index(&headers, "done"),
+    };
+
+    let rows = reader
+        .records()
+        .map(|record| {
+            let record = record.map_err(|err| RowError {
+                row: err.position().map(|p| p.line()).unwrap_or_default(),
+                message: err.to_string(),
+            })?;
+            let row = record.position().map(|p| p.line()).unwrap_or_default();
+
+            let description = record.get(description_index).unwrap_or_default();
+            if description.is_empty() {
+                return Err(RowError {
+                    row,
+                    message: "description is empty".to_string(),
+                });
+            }
+
+            let done = match done_index.and_then(|index| record.get(index)) {
+                Some(done) => parse_done(done).ok_or_else(|| RowError {
+                    row,
+                    message: format!("\"{done}\" is not a valid done value"),
+                })?,
+                None => false,
+            };
+
+            Ok(ImportRow {
+                description: description.to_string(),
+                done,
+            })
+        })
+        .collect();
+
+    Ok(rows)
+}
+
+fn column_index(headers: &StringRecord, name: &str) -> Option<usize> {
+    headers
+        .iter()
+        .position(|header| header.eq_ignore_ascii_case(name.trim()))
+}
+
+fn parse_done(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "y" | "x" | "1" => Some(true),
+        "false" | "no" | "n" | "0" | "" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,352 @@
+//! iCalendar (RFC 5545) calendars of `VTODO` components.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::endpoints::Todo;
+
+use super::{ImportRow, RowError};
+
+const PRODID: &str = "-//axum-sqlx-mockall-todos//todos//EN";
+/// Lines longer than this many octets, excluding the line break, must be folded
+const MAX_LINE_OCTETS: usize = 75;
+
+/// Renders todos as a calendar with one `VTODO` per todo. Todos have no due date, so no
+/// `DUE` property is written.
+pub fn write_calendar(todos: &[Todo], now: SystemTime) -> String {
+    let dtstamp = format_utc(now);
+
+    let mut calendar = String::new();
+    write_line(&mut calendar, "BEGIN:VCALENDAR");
+    write_line(&mut calendar, "VERSION:2.0");
+    write_line(&mut calendar, &format!("PRODID:{PRODID}"));
+    for todo in todos {
+        let status = if todo.done {
+            "COMPLETED"
+        } else {
+            "NEEDS-ACTION"
+        };
+
+        write_line(&mut calendar, "BEGIN:VTODO");
+        write_line(&mut calendar, &format!("UID:{}", uid(todo.id)));
+        write_line(&mut calendar, &format!("DTSTAMP:{dtstamp}"));
+        write_line(
+            &mut calendar,
+            &format!("SUMMARY:{}", escape(&todo.description)),
+        );
+        write_line(&mut calendar, &format!("STATUS:{status}"));
+        write_line(&mut calendar, "END:VTODO");
+    }
+    write_line(&mut calendar, "END:VCALENDAR");
+
+    calendar
+}
+
+/// Reads every `VTODO` in a calendar. The `SUMMARY` becomes the description and a `STATUS`
+/// of `COMPLETED` marks the todo as done. Other components and properties are ignored,
+/// including components nested in a `VTODO` such as a `VALARM`.
+pub fn parse(input: &str) -> Result<Vec<Result<ImportRow, RowError>>, String> {
+    let mut rows = vec![];
+    let mut seen_calendar = false;
+    let mut current: Option<OpenTodo> = None;
+
+    for (line_number, line) in unfold(input) {
+        let Some((name, value)) = split_content_line(&line) else {
+            if current.is_some() {
+                rows.push(Err(RowError {
+                    row: line_number,
+                    message: format!("invalid content line \"{line}\""),
+                }));
+            }
+            continue;
+        };
+
+        match (name.to_ascii_uppercase().as_str(), &mut current) {
+            // Such as a VALARM, whose properties are not the todo's
+            ("BEGIN", Some(todo)) => todo.nested += 1,
+            ("END", Some(todo)) if todo.nested > 0 => todo.nested -= 1,
+            ("BEGIN", _) if value.eq_ignore_ascii_case("VCALENDAR") => seen_calendar = true,
+            ("BEGIN", None) if value.eq_ignore_ascii_case("VTODO") => {
+                current = Some(OpenTodo {
+                    row: line_number,
+                    summary: None,
+                    done: false,
+                    nested: 0,
+                });
+            }
+            ("END", Some(_)) if value.eq_ignore_ascii_case("VTODO") => {
+                let OpenTodo {
+                    row, summary, done, ..
+                } = current.take().unwrap();
+                rows.push(match summary {
+                    Some(description) if !description.is_empty() => {
+                        Ok(ImportRow { description, done })
+                    }
+                    _ => Err(RowError {
+                        row,
+                        message: "VTODO has no SUMMARY".to_string(),
+                    }),
+                });
+            }
+            ("SUMMARY", Some(todo)) if todo.nested == 0 => todo.summary = Some(unescape(value)),
+            ("STATUS", Some(todo)) if todo.nested == 0 => {
+                todo.done = value.eq_ignore_ascii_case("COMPLETED")
+            }
+            _ => {}
+        }
+    }
+
+    if !seen_calendar {
+        return Err("not an iCalendar file".to_string());
+    }
+    if let Some(OpenTodo { row, .. }) = current {
+        rows.push(Err(RowError {
+            row,
+            message: "VTODO is not terminated".to_string(),
+        }));
+    }
+
+    Ok(rows)
+}
+
+/// A VTODO being read
+struct OpenTodo {
+    /// The line it started on
+    row: u64,
+    summary: Option<String>,
+    done: bool,
+    /// How many components within it are open
+    nested: usize,
+}
+
+fn uid(id: i64) -> String {
+    format!("todo-{id}@axum-sqlx-mockall-todos")
+}
+
+/// Appends a content line, folding it so that no line exceeds 75 octets. Folds never split
+/// a UTF-8 character.
+fn write_line(output: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            output.push_str("\r\n ");
+            // The leading space counts towards the length of the continuation line
+            octets = 1;
+        }
+        output.push(c);
+        octets += c.len_utf8();
+    }
+    output.push_str("\r\n");
+}
+
+/// Splits input into logical content lines, joining folded continuation lines. Each line is
+/// paired with the line number it starts on.
+fn unfold(input: &str) -> Vec<(u64, String)> {
+    let mut lines: Vec<(u64, String)> = vec![];
+    for (index, line) in input.lines().enumerate() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some((_, previous))) => previous.push_str(continuation),
+            _ if line.is_empty() => {}
+            _ => lines.push((index as u64 + 1, line.to_string())),
+        }
+    }
+    lines
+}
+
+/// Splits a content line into its name, without any parameters, and its value
+fn split_content_line(line: &str) -> Option<(&str, &str)> {
+    // Parameter values may contain colons if they are quoted
+    let mut quoted = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            quoted = !quoted;
+            None
+        }
+        ':' if !quoted => Some(i),
+        _ => None,
+    })?;
+
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+    let name = name.split(';').next().unwrap_or(name);
+    Some((name, value))
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n' | 'N') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Formats a time as a UTC date-time, e.g. `20240101T120000Z`
+fn format_utc(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Converts days since the epoch to a civil date, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_write_calendar() {
+        let todos = [
+            Todo {
+                id: 1,
+                description: "Buy milk, eggs; bread".to_string(),
+                done: false,
+            },
+            Todo {
+                id: 2,
+                description: "test 2".to_string(),
+                done: true,
+            },
+        ];
+        let now = UNIX_EPOCH + Duration::from_secs(1700000000);
+
+        assert_eq!(
+            write_calendar(&todos, now),
+            "BEGIN:VCALENDAR\r\n\
+            VERSION:2.0\r\n\
+            PRODID:-//axum-sqlx-mockall-todos//todos//EN\r\n\
+            BEGIN:VTODO\r\n\
+            UID:todo-1@axum-sqlx-mockall-todos\r\n\
+            DTSTAMP:20231114T221320Z\r\n\
+            SUMMARY:Buy milk\\, eggs\\; bread\r\n\
+            STATUS:NEEDS-ACTION\r\n\
+            END:VTODO\r\n\
+            BEGIN:VTODO\r\n\
+            UID:todo-2@axum-sqlx-mockall-todos\r\n\
+            DTSTAMP:20231114T221320Z\r\n\
+            SUMMARY:test 2\r\n\
+            STATUS:COMPLETED\r\n\
+            END:VTODO\r\n\
+            END:VCALENDAR\r\n"
+        );
+    }
+
+    #[test]
+    fn test_fold_long_lines() {
+        let description = "é".repeat(50);
+        let todos = [Todo {
+            id: 1,
+            description: description.clone(),
+            done: false,
+        }];
+
+        let calendar = write_calendar(&todos, UNIX_EPOCH);
+
+        assert!(calendar.split("\r\n").all(|line| line.len() <= 75));
+        assert!(calendar.contains("\r\n é"));
+        assert_eq!(
+            parse(&calendar).unwrap(),
+            vec![Ok(ImportRow {
+                description,
+                done: false
+            })]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let calendar = "BEGIN:VCALENDAR\n\
+            VERSION:2.0\n\
+            BEGIN:VEVENT\n\
+            SUMMARY:Not a todo\n\
+            END:VEVENT\n\
+            BEGIN:VTODO\n\
+            SUMMARY;LANGUAGE=en:Line one\\nline two\\, with\n  a fold\n\
+            STATUS:COMPLETED\n\
+            END:VTODO\n\
+            BEGIN:VTODO\n\
+            STATUS:NEEDS-ACTION\n\
+            END:VTODO\n\
+            END:VCALENDAR\n";
+
+        assert_eq!(
+            parse(calendar).unwrap(),
+            vec![
+                Ok(ImportRow {
+                    description: "Line one\nline two, with a fold".to_string(),
+                    done: true
+                }),
+                Err(RowError {
+                    row: 11,
+                    message: "VTODO has no SUMMARY".to_string()
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_nested_components() {
+        let calendar = "BEGIN:VCALENDAR\n\
+            BEGIN:VTODO\n\
+            SUMMARY:Pay rent\n\
+            STATUS:COMPLETED\n\
+            BEGIN:VALARM\n\
+            ACTION:DISPLAY\n\
+            SUMMARY:Reminder\n\
+            STATUS:NEEDS-ACTION\n\
+            END:VALARM\n\
+            END:VTODO\n\
+            END:VCALENDAR\n";
+
+        assert_eq!(
+            parse(calendar).unwrap(),
+            vec![Ok(ImportRow {
+                description: "Pay rent".to_string(),
+                done: true
+            })]
+        );
+    }
+}
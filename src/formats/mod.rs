@@ -0,0 +1,22 @@
+//! Conversions between todos and other file formats, used to import and export todos in bulk.
+
+use serde::Serialize;
+
+pub mod csv;
+pub mod ical;
+pub mod todotxt;
+
+/// A todo read from an imported file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportRow {
+    pub description: String,
+    pub done: bool,
+}
+
+/// Why a single row of an imported file could not be read
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RowError {
+    /// The line of the file the row starts on
+    pub row: u64,
+    pub message: String,
+}
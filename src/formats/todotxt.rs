@@ -0,0 +1,311 @@
+//! The [todo.txt](https://github.com/todotxt/todo.txt) format, one task per line.
+//!
+//! A todo's description holds everything about a task except whether it is done, so
+//! priorities, `+project` and `@context` tokens are kept in the description as written.
+//! Descriptions that would otherwise be read back as something else, such as one starting
+//! with `x ` or a date, are written after a `\\`, which marks the rest of the line as the
+//! description exactly.
+
+use crate::endpoints::{Todo, TodoAdd};
+
+use super::{ImportRow, RowError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Task {
+    pub done: bool,
+    /// A priority from `A` to `Z`, written as `(A)` at the start of a task
+    pub priority: Option<char>,
+    /// The rest of the task, without its priority or dates
+    pub text: String,
+}
+
+impl Task {
+    /// Parses a single line, returning `None` if it is blank. Completion and creation dates
+    /// are dropped as todos do not store them.
+    pub fn parse(line: &str) -> Option<Task> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+
+        let (done, rest) = match line.strip_prefix("x ") {
+            // The completion date, which is followed by the creation date if there is one
+            Some(rest) => (true, strip_date(strip_date(rest.trim_start()))),
+            None if line == "x" => (true, ""),
+            None => (false, line),
+        };
+
+        if let Some(text) = rest.strip_prefix('\\') {
+            return Some(Task {
+                done,
+                priority: None,
+                text: text.to_string(),
+            });
+        }
+        Some(Task {
+            done,
+            ..Task::from_description(rest)
+        })
+    }
+
+    /// Parses a task that is not done, without looking for an `x ` prefix
+    fn from_description(description: &str) -> Task {
+        let mut rest = description.trim();
+
+        let priority = match rest.as_bytes() {
+            [b'(', p @ b'A'..=b'Z', b')', b' ', ..] => {
+                rest = rest[4..].trim_start();
+                Some(*p as char)
+            }
+            _ => None,
+        };
+
+        Task {
+            done: false,
+            priority,
+            // The creation date of a task that is not done comes after its priority
+            text: strip_date(rest).to_string(),
+        }
+    }
+
+    /// The `+project` tokens of the task, without the `+`
+    pub fn projects(&self) -> impl Iterator<Item = &str> {
+        tagged(&self.text, '+')
+    }
+
+    /// The `@context` tokens of the task, without the `@`
+    pub fn contexts(&self) -> impl Iterator<Item = &str> {
+        tagged(&self.text, '@')
+    }
+
+    /// The description of a todo for this task
+    pub fn description(&self) -> String {
+        match self.priority {
+            Some(priority) => format!("({priority}) {}", self.text),
+            None => self.text.clone(),
+        }
+    }
+
+    pub fn to_todo_add(&self) -> TodoAdd {
+        TodoAdd {
+            description: self.description(),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        let description = self.description();
+        let prefix = if self.done { "x " } else { "" };
+
+        let line = format!("{prefix}{description}");
+        let read_back = Task::parse(&line)
+            .is_some_and(|task| task.done == self.done && task.description() == description);
+        if read_back {
+            line
+        } else {
+            format!("{prefix}\\{description}")
+        }
+    }
+}
+
+impl From<&Todo> for Task {
+    fn from(todo: &Todo) -> Self {
+        // Each task must stay on a single line
+        let description = todo.description.split_whitespace().collect::<Vec<_>>();
+        Task {
+            done: todo.done,
+            priority: None,
+            text: description.join(" "),
+        }
+    }
+}
+
+/// Writes todos as todo.txt, one line per todo.
+pub fn write_todos(todos: &[Todo]) -> String {
+    todos
+        .iter()
+        .map(|todo| Task::from(todo).to_line() + "\n")
+        .collect()
+}
+
+/// Reads todos from todo.txt, skipping blank lines.
+pub fn parse(input: &str) -> Vec<Result<ImportRow, RowError>> {
+    input
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| Some((index as u64 + 1, Task::parse(line)?)))
+        .map(|(row, task)| {
+            if task.text.is_empty() {
+                return Err(RowError {
+                    row,
+                    message: "task is empty".to_string(),
+                });
+            }
+            Ok(ImportRow {
+                description: task.to_todo_add().description,
+                done: task.done,
+            })
+        })
+        .collect()
+}
+
+fn strip_date(text: &str) -> &str {
+    let is_date = text.len() >= 10
+        && text.as_bytes()[..10]
+            .iter()
+            .enumerate()
+            .all(|(i, b)| match i {
+                4 | 7 => *b == b'-',
+                _ => b.is_ascii_digit(),
+            });
+    match text.get(10..) {
+        Some(rest) if is_date && (rest.is_empty() || rest.starts_with(' ')) => rest.trim_start(),
+        _ => text,
+    }
+}
+
+fn tagged(text: &str, tag: char) -> impl Iterator<Item = &str> {
+    text.split_whitespace()
+        .filter_map(move |word| word.strip_prefix(tag))
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_task() {
+        let task = Task::parse("x 2024-01-02 2024-01-01 (A) Call Mom +family @phone").unwrap();
+
+        assert_eq!(
+            task,
+            Task {
+                done: true,
+                priority: Some('A'),
+                text: "Call Mom +family @phone".to_string(),
+            }
+        );
+        assert_eq!(task.projects().collect::<Vec<_>>(), ["family"]);
+        assert_eq!(task.contexts().collect::<Vec<_>>(), ["phone"]);
+        assert_eq!(task.to_line(), "x (A) Call Mom +family @phone");
+        assert_eq!(
+            task.to_todo_add().description,
+            "(A) Call Mom +family @phone"
+        );
+    }
+
+    #[test]
+    fn test_parse_not_a_priority() {
+        let task = Task::parse("(a) lowercase is not a priority").unwrap();
+
+        assert_eq!(task.priority, None);
+        assert_eq!(task.text, "(a) lowercase is not a priority");
+        assert!(!task.done);
+
+        let task = Task::parse("xylophone lessons").unwrap();
+
+        assert!(!task.done);
+        assert_eq!(task.text, "xylophone lessons");
+    }
+
+    #[test]
+    fn test_descriptions_like_todo_txt() {
+        let todos: Vec<_> = [
+            ("x marks the spot", false),
+            ("2024-01-01 Pay rent", false),
+            ("(A) 2024-01-01 Pay rent", true),
+            ("2024-01-02 Renew passport", true),
+            ("\\ backslash", false),
+        ]
+        .into_iter()
+        .map(|(description, done)| Todo {
+            id: 1,
+            description: description.to_string(),
+            done,
+        })
+        .collect();
+
+        let text = write_todos(&todos);
+
+        assert_eq!(
+            text,
+            "\\x marks the spot\n\\2024-01-01 Pay rent\nx \\(A) 2024-01-01 Pay rent\n\
+             x \\2024-01-02 Renew passport\n\\\\ backslash\n"
+        );
+
+        let rows = parse(&text);
+        let expected: Vec<_> = todos
+            .iter()
+            .map(|todo| {
+                Ok(ImportRow {
+                    description: todo.description.clone(),
+                    done: todo.done,
+                })
+            })
+            .collect();
+
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let todos = vec![
+            Todo {
+                id: 1,
+                description: "(B) Buy milk @store +groceries".to_string(),
+                done: false,
+            },
+            Todo {
+                id: 2,
+                description: "Plan trip +vacation".to_string(),
+                done: true,
+            },
+            Todo {
+                id: 3,
+                description: "(A) File taxes".to_string(),
+                done: true,
+            },
+        ];
+
+        let text = write_todos(&todos);
+
+        assert_eq!(
+            text,
+            "(B) Buy milk @store +groceries\nx Plan trip +vacation\nx (A) File taxes\n"
+        );
+
+        let rows = parse(&text);
+        let expected: Vec<_> = todos
+            .iter()
+            .map(|todo| {
+                Ok(ImportRow {
+                    description: todo.description.clone(),
+                    done: todo.done,
+                })
+            })
+            .collect();
+
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse("Buy milk\n\nx \nx\n"),
+            vec![
+                Ok(ImportRow {
+                    description: "Buy milk".to_string(),
+                    done: false
+                }),
+                Err(RowError {
+                    row: 3,
+                    message: "task is empty".to_string()
+                }),
+                Err(RowError {
+                    row: 4,
+                    message: "task is empty".to_string()
+                }),
+            ]
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! A GraphQL API over the same provider and change feed as the REST endpoints, with
+//! queries for todos, mutations to add and update them, and a subscription to changes.
+
+use std::collections::HashSet;
+
+use async_graphql::{Context, Error, ErrorExtensions, Object, Schema, SimpleObject, Subscription};
+use futures_util::{stream, Stream};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    app::AppState,
+    changes::{Change, ChangeKind},
+    endpoints::{Actor, Todo},
+    provider::{ProviderError, TodoProvider},
+    rate_limit,
+    resilience::Unavailable,
+};
+
+pub type TodoSchema<A> = Schema<Query<A>, Mutation<A>, SubscriptionRoot<A>>;
+
+/// Builds the schema, resolving everything through `state`. The actor making a change is
+/// taken from an [`Actor`] in the request data, if there is one.
+pub fn schema<A: AppState>(state: A) -> TodoSchema<A> {
+    Schema::build(
+        Query(state.clone()),
+        Mutation(state.clone()),
+        SubscriptionRoot(state),
+    )
+    .finish()
+}
+
+pub struct Query<A>(A);
+
+#[Object]
+impl<A: AppState> Query<A> {
+    async fn todos(&self) -> Result<Vec<Todo>, Error> {
+        self.0.provider().get_todos().await.map_err(provider_error)
+    }
+
+    async fn todo(&self, id: i64) -> Result<Option<Todo>, Error> {
+        self.0.provider().get_todo(id).await.map_err(provider_error)
+    }
+}
+
+pub struct Mutation<A>(A);
+
+#[Object]
+impl<A: AppState> Mutation<A> {
+    async fn add_todo(&self, ctx: &Context<'_>, description: String) -> Result<Todo, Error> {
+        let todo = self
+            .0
+            .provider()
+            .add_todo(&description, actor(ctx))
+            .await
+            .map_err(provider_error)?;
+        self.0.changes().publish(ChangeKind::Created, todo.clone());
+
+        Ok(todo)
+    }
+
+    async fn update_todo(
+        &self,
+        ctx: &Context<'_>,
+        id: i64,
+        description: String,
+        done: bool,
+    ) -> Result<Todo, Error> {
+        let todo = self
+            .0
+            .provider()
+            .update_todo(id, &description, done, actor(ctx))
+            .await
+            .map_err(provider_error)?
+            .ok_or_else(|| Error::new(format!("todo {id} not found")))?;
+        self.0.changes().publish(ChangeKind::Updated, todo.clone());
+
+        Ok(todo)
+    }
+}
+
+pub struct SubscriptionRoot<A>(A);
+
+#[Subscription]
+impl<A: AppState> SubscriptionRoot<A> {
+    /// Changes made from now on, to the todos with the given `ids` or to every todo. The
+    /// stream ends if the subscriber falls too far behind.
+    async fn changes(&self, ids: Option<Vec<i64>>) -> impl Stream<Item = TodoChange> {
+        let (_, receiver) = self.0.changes().subscribe(None);
+        let ids: Option<HashSet<i64>> = ids.map(|ids| ids.into_iter().collect());
+
+        stream::unfold((receiver, ids), |(mut receiver, ids)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(change)
+                        if ids
+                            .as_ref()
+                            .is_some_and(|ids| !ids.contains(&change.todo.id)) =>
+                    {
+                        continue
+                    }
+                    Ok(change) => return Some((TodoChange::from(change), (receiver, ids))),
+                    Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct TodoChange {
+    pub id: u64,
+    pub kind: ChangeKind,
+    pub todo: Todo,
+}
+
+impl From<Change> for TodoChange {
+    fn from(Change { id, kind, todo }: Change) -> Self {
+        TodoChange { id, kind, todo }
+    }
+}
+
+fn actor<'a>(ctx: &Context<'a>) -> &'a str {
+    ctx.data_opt::<Actor>()
+        .map_or(Actor::ANONYMOUS, |actor| actor.0.as_str())
+}
+
+/// Maps an open circuit to `SERVICE_UNAVAILABLE`, and anything else to an internal error
+fn provider_error(ProviderError(err): ProviderError) -> Error {
+    if let Some(unavailable) = err.downcast_ref::<Unavailable>() {
+        return Error::new("service unavailable").extend_with(|_, extensions| {
+            extensions.set("code", "SERVICE_UNAVAILABLE");
+            extensions.set(
+                "retryAfter",
+                rate_limit::whole_seconds(unavailable.retry_after),
+            );
+        });
+    }
+    tracing::error!("{}", err);
+    Error::new("internal error")
+}
@@ -0,0 +1,169 @@
+//! A gRPC service over the same provider and change feed as the REST endpoints, defined
+//! in `proto/todos.proto`. The router serves it on the same port as everything else, at
+//! the paths gRPC clients call.
+
+use tonic::{server::NamedService, Request, Response, Status};
+
+use crate::{
+    app::AppState,
+    changes::ChangeKind,
+    endpoints::{self, Actor},
+    provider::{ProviderError, TodoProvider},
+    rate_limit,
+    resilience::Unavailable,
+};
+
+use proto::{
+    todos_server::{Todos, TodosServer},
+    GetTodoRequest, GetTodosRequest, GetTodosResponse, Todo, TodoAdd, TodoUpdate,
+};
+
+pub mod proto {
+    tonic::include_proto!("todos.v1");
+}
+
+pub fn service<A: AppState>(state: A) -> TodosServer<TodoService<A>> {
+    TodosServer::new(TodoService(state))
+}
+
+/// The route to serve the service at. gRPC calls are made to `/<package>.<service>/<method>`,
+/// which no other route uses.
+pub fn route<A: AppState>() -> String {
+    format!("/{}/*method", TodosServer::<TodoService<A>>::NAME)
+}
+
+pub struct TodoService<A>(A);
+
+#[tonic::async_trait]
+impl<A: AppState> Todos for TodoService<A> {
+    async fn get_todos(
+        &self,
+        _request: Request<GetTodosRequest>,
+    ) -> Result<Response<GetTodosResponse>, Status> {
+        let todos = self
+            .0
+            .provider()
+            .get_todos()
+            .await
+            .map_err(provider_error)?;
+
+        Ok(Response::new(GetTodosResponse {
+            todos: todos.into_iter().map(Todo::from).collect(),
+        }))
+    }
+
+    async fn get_todo(&self, request: Request<GetTodoRequest>) -> Result<Response<Todo>, Status> {
+        let GetTodoRequest { id } = request.into_inner();
+
+        match self
+            .0
+            .provider()
+            .get_todo(id)
+            .await
+            .map_err(provider_error)?
+        {
+            Some(todo) => Ok(Response::new(todo.into())),
+            None => Err(not_found(id)),
+        }
+    }
+
+    async fn add_todo(&self, request: Request<TodoAdd>) -> Result<Response<Todo>, Status> {
+        let actor = actor(&request);
+        let TodoAdd { description } = request.into_inner();
+
+        let todo = self
+            .0
+            .provider()
+            .add_todo(&description, &actor)
+            .await
+            .map_err(provider_error)?;
+        self.0.changes().publish(ChangeKind::Created, todo.clone());
+
+        Ok(Response::new(todo.into()))
+    }
+
+    async fn update_todo(&self, request: Request<TodoUpdate>) -> Result<Response<Todo>, Status> {
+        let actor = actor(&request);
+        let TodoUpdate {
+            id,
+            description,
+            done,
+        } = request.into_inner();
+
+        let todo = self
+            .0
+            .provider()
+            .update_todo(id, &description, done, &actor)
+            .await
+            .map_err(provider_error)?
+            .ok_or_else(|| not_found(id))?;
+        self.0.changes().publish(ChangeKind::Updated, todo.clone());
+
+        Ok(Response::new(todo.into()))
+    }
+}
+
+impl From<endpoints::Todo> for Todo {
+    fn from(todo: endpoints::Todo) -> Self {
+        Todo {
+            id: todo.id,
+            description: todo.description,
+            done: todo.done,
+        }
+    }
+}
+
+/// The actor named in the request metadata, under the same name as the REST header
+fn actor<T>(request: &Request<T>) -> String {
+    request
+        .metadata()
+        .get(Actor::HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .unwrap_or(Actor::ANONYMOUS)
+        .to_string()
+}
+
+fn not_found(id: i64) -> Status {
+    Status::not_found(format!("todo {id} not found"))
+}
+
+/// Maps an open circuit to `UNAVAILABLE`, and anything else to an internal error
+fn provider_error(ProviderError(err): ProviderError) -> Status {
+    if let Some(unavailable) = err.downcast_ref::<Unavailable>() {
+        let mut status = Status::unavailable("service unavailable");
+        status.metadata_mut().insert(
+            "retry-after",
+            rate_limit::whole_seconds(unavailable.retry_after).into(),
+        );
+        return status;
+    }
+    tracing::error!("{}", err);
+    Status::internal("internal error")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tonic::Code;
+
+    use super::*;
+
+    #[test]
+    fn test_provider_error() {
+        let status = provider_error(ProviderError(
+            Unavailable {
+                retry_after: Duration::from_millis(2500),
+            }
+            .into(),
+        ));
+        assert_eq!(status.code(), Code::Unavailable);
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "3");
+
+        let status = provider_error(ProviderError(anyhow::anyhow!("database is corrupt")));
+        assert_eq!(status.code(), Code::Internal);
+        assert_eq!(status.message(), "internal error");
+    }
+}
@@ -0,0 +1,244 @@
+//! Composing [`TodoProvider`](crate::provider::TodoProvider) decorators, in the manner of
+//! tower's `Layer` and `ServiceBuilder`.
+//!
+//! A [`ProviderLayer`] wraps a provider in another, such as a cache. A [`ProviderBuilder`]
+//! stacks layers and applies them to a provider, giving a single provider for the app:
+//!
+//! ```
+//! # use axum_sqlx_mockall_todos::{
+//! #     cache::{CacheConfig, CacheLayer, CachedTodoProvider},
+//! #     layer::ProviderBuilder,
+//! #     provider::MockTodoProvider,
+//! #     resilience::{ResilienceConfig, ResilienceLayer, ResilientTodoProvider},
+//! # };
+//! let provider: CachedTodoProvider<ResilientTodoProvider<MockTodoProvider>> =
+//!     ProviderBuilder::new()
+//!         .layer(CacheLayer::new(CacheConfig::default()))
+//!         .layer(ResilienceLayer::new(ResilienceConfig::default()))
+//!         .build(MockTodoProvider::new());
+//! ```
+
+/// Wraps a provider in another
+pub trait ProviderLayer<P> {
+    type Provider;
+
+    fn layer(&self, inner: P) -> Self::Provider;
+}
+
+/// The layer that leaves providers as they are
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Identity;
+
+impl<P> ProviderLayer<P> for Identity {
+    type Provider = P;
+
+    fn layer(&self, inner: P) -> Self::Provider {
+        inner
+    }
+}
+
+/// Applies `inner` and then `outer`
+#[derive(Clone, Debug)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<P, Inner, Outer> ProviderLayer<P> for Stack<Inner, Outer>
+where
+    Inner: ProviderLayer<P>,
+    Outer: ProviderLayer<Inner::Provider>,
+{
+    type Provider = Outer::Provider;
+
+    fn layer(&self, inner: P) -> Self::Provider {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+/// A layer from a function, for decorators without a layer of their own
+pub fn layer_fn<F>(f: F) -> LayerFn<F> {
+    LayerFn(f)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LayerFn<F>(F);
+
+impl<P, F, Out> ProviderLayer<P> for LayerFn<F>
+where
+    F: Fn(P) -> Out,
+{
+    type Provider = Out;
+
+    fn layer(&self, inner: P) -> Self::Provider {
+        (self.0)(inner)
+    }
+}
+
+/// Stacks layers to apply to a provider. Layers added first are outermost, so see calls
+/// first.
+#[derive(Clone, Debug)]
+pub struct ProviderBuilder<L> {
+    layer: L,
+}
+
+impl ProviderBuilder<Identity> {
+    pub fn new() -> Self {
+        ProviderBuilder { layer: Identity }
+    }
+}
+
+impl Default for ProviderBuilder<Identity> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L> ProviderBuilder<L> {
+    /// Adds a layer inside those already added
+    pub fn layer<T>(self, layer: T) -> ProviderBuilder<Stack<T, L>> {
+        ProviderBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    pub fn build<P>(&self, provider: P) -> L::Provider
+    where
+        L: ProviderLayer<P>,
+    {
+        self.layer.layer(provider)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        cache::{CacheConfig, CacheLayer},
+        endpoints::{BatchMode, BatchOperation, BatchOutcome, Todo, TodoRevision, TrashedTodo},
+        provider::{MockTodoProvider, ProviderError, TodoProvider},
+        resilience::{ResilienceConfig, ResilienceLayer},
+    };
+
+    /// Records the name of each layer that `get_todos` passes through
+    struct Recording<P> {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+        inner: P,
+    }
+
+    #[async_trait]
+    impl<P: TodoProvider + Send + Sync> TodoProvider for Recording<P> {
+        async fn get_todos(&self) -> Result<Vec<Todo>, ProviderError> {
+            self.calls.lock().unwrap().push(self.name);
+            self.inner.get_todos().await
+        }
+
+        async fn get_todo(&self, id: i64) -> Result<Option<Todo>, ProviderError> {
+            self.inner.get_todo(id).await
+        }
+
+        async fn add_todo(&self, description: &str, actor: &str) -> Result<Todo, ProviderError> {
+            self.inner.add_todo(description, actor).await
+        }
+
+        async fn update_todo(
+            &self,
+            id: i64,
+            description: &str,
+            done: bool,
+            actor: &str,
+        ) -> Result<Option<Todo>, ProviderError> {
+            self.inner.update_todo(id, description, done, actor).await
+        }
+
+        async fn delete_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError> {
+            self.inner.delete_todo(id, actor).await
+        }
+
+        async fn get_trash(&self) -> Result<Vec<TrashedTodo>, ProviderError> {
+            self.inner.get_trash().await
+        }
+
+        async fn restore_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError> {
+            self.inner.restore_todo(id, actor).await
+        }
+
+        async fn purge_trash(
+            &self,
+            retention: std::time::Duration,
+            actor: &str,
+        ) -> Result<u64, ProviderError> {
+            self.inner.purge_trash(retention, actor).await
+        }
+
+        async fn get_history(&self, id: i64) -> Result<Vec<TodoRevision>, ProviderError> {
+            self.inner.get_history(id).await
+        }
+
+        async fn apply_batch(
+            &self,
+            operations: Vec<BatchOperation>,
+            mode: BatchMode,
+            actor: &str,
+        ) -> Result<BatchOutcome, ProviderError> {
+            self.inner.apply_batch(operations, mode, actor).await
+        }
+    }
+
+    fn recording<P>(
+        name: &'static str,
+        calls: &Arc<Mutex<Vec<&'static str>>>,
+    ) -> impl ProviderLayer<P, Provider = Recording<P>> {
+        let calls = calls.clone();
+        layer_fn(move |inner| Recording {
+            name,
+            calls: calls.clone(),
+            inner,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_layers_apply_in_order() {
+        let mut inner = MockTodoProvider::new();
+        inner.expect_get_todos().times(1).returning(|| Ok(vec![]));
+
+        let calls = Arc::new(Mutex::new(vec![]));
+        let provider = ProviderBuilder::new()
+            .layer(recording("outer", &calls))
+            .layer(recording("middle", &calls))
+            .layer(recording("inner", &calls))
+            .build(inner);
+
+        assert!(provider.get_todos().await.is_ok());
+        assert_eq!(*calls.lock().unwrap(), vec!["outer", "middle", "inner"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cache_over_resilience() {
+        let mut inner = MockTodoProvider::new();
+        inner
+            .expect_get_todos()
+            .times(1)
+            .returning(|| Err(ProviderError(sqlx::Error::PoolTimedOut.into())));
+        inner.expect_get_todos().times(1).returning(|| Ok(vec![]));
+
+        let provider = ProviderBuilder::new()
+            .layer(CacheLayer::new(CacheConfig::default()))
+            .layer(ResilienceLayer::new(ResilienceConfig::default()))
+            .build(inner);
+
+        // The failure is retried within the cache, whose later hits call nothing
+        for _ in 0..3 {
+            assert!(provider.get_todos().await.is_ok());
+        }
+        assert_eq!(provider.stats().hits, 2);
+    }
+}
@@ -1,20 +1,72 @@
 use app::AppState;
-use db::SqliteTodoProvider;
+use changes::ChangeFeed;
+use config::AppConfig;
+use db::{SqliteBackupProvider, SqliteTodoProvider, SqliteWebhookProvider};
+use provider::TodoProvider;
+use rate_limit::RateLimiter;
 
+pub mod admin;
 pub mod app;
+pub mod body_limit;
+pub mod cache;
+pub mod changes;
+pub mod client;
+pub mod config;
+pub mod cors;
 pub mod db;
 pub mod endpoints;
+pub mod formats;
+pub mod graphql;
+pub mod grpc;
+pub mod layer;
+pub mod listener;
 pub mod provider;
+pub mod rate_limit;
+pub mod request_id;
+pub mod resilience;
+pub mod server;
+pub mod snapshots;
+pub mod webhooks;
+pub mod websocket;
 
+/// The app's state, with todos from `P`, which is a [`SqliteTodoProvider`] or one wrapped in
+/// layers by a [`ProviderBuilder`](layer::ProviderBuilder)
 #[derive(Clone)]
-pub struct SqliteAppState {
-    pub provider: SqliteTodoProvider,
+pub struct SqliteAppState<P = SqliteTodoProvider> {
+    pub provider: P,
+    pub webhooks: SqliteWebhookProvider,
+    pub backups: SqliteBackupProvider,
+    pub changes: ChangeFeed,
+    pub rate_limiter: RateLimiter,
+    pub config: AppConfig,
 }
 
-impl AppState for SqliteAppState {
-    type P = SqliteTodoProvider;
+impl<P: TodoProvider + Clone + Send + Sync + 'static> AppState for SqliteAppState<P> {
+    type P = P;
+    type W = SqliteWebhookProvider;
+    type B = SqliteBackupProvider;
 
     fn provider(&self) -> &Self::P {
         &self.provider
     }
+
+    fn webhooks(&self) -> &Self::W {
+        &self.webhooks
+    }
+
+    fn backups(&self) -> &Self::B {
+        &self.backups
+    }
+
+    fn changes(&self) -> &ChangeFeed {
+        &self.changes
+    }
+
+    fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    fn config(&self) -> &AppConfig {
+        &self.config
+    }
 }
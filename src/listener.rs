@@ -0,0 +1,363 @@
+//! The sockets the server can listen on: TCP, Unix domain sockets for local sidecars, and
+//! sockets opened by systemd for socket activation.
+
+use std::{
+    env, fmt, fs, io,
+    net::SocketAddr,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{FromRawFd, IntoRawFd, RawFd},
+        net as unix,
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+/// The first file descriptor passed by systemd, following stdin, stdout and stderr
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Whether the socket passed by systemd has been taken
+static SYSTEMD_SOCKET_TAKEN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenerConfig {
+    Tcp(SocketAddr),
+    /// A Unix domain socket at `path`, replacing any socket left there by an earlier run
+    Unix {
+        path: PathBuf,
+        /// Permissions to give the socket, such as `0o660`, rather than those from the umask
+        mode: Option<u32>,
+    },
+    /// The socket passed by systemd through `LISTEN_FDS`, as described in `sd_listen_fds(3)`
+    Systemd,
+}
+
+/// Parses `tcp:<address>` or just `<address>`, `unix:<path>` and `systemd`
+impl FromStr for ListenerConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "systemd" {
+            return Ok(ListenerConfig::Systemd);
+        }
+        if let Some(path) = s.strip_prefix("unix:") {
+            anyhow::ensure!(!path.is_empty(), "no path given for Unix socket");
+            return Ok(ListenerConfig::Unix {
+                path: path.into(),
+                mode: None,
+            });
+        }
+        let address = s.strip_prefix("tcp:").unwrap_or(s);
+        address
+            .parse()
+            .map(ListenerConfig::Tcp)
+            .map_err(|_| anyhow::anyhow!("invalid listen address {s}"))
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix {
+        listener: UnixListener,
+        /// Where clients connect, which is not the listener's own address if the socket
+        /// was moved after binding
+        path: Option<PathBuf>,
+    },
+}
+
+impl Listener {
+    pub async fn bind(config: &ListenerConfig) -> io::Result<Self> {
+        match config {
+            ListenerConfig::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+            ListenerConfig::Unix { path, mode } => {
+                remove_stale_socket(path)?;
+
+                // Bound in a directory only this user can enter, so that no client can
+                // connect before the permissions are set, and then moved into place
+                let parent = match path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                let private = tempfile::Builder::new()
+                    .prefix(".bind")
+                    .tempdir_in(parent)?;
+                let bound = private.path().join("socket");
+                let listener = UnixListener::bind(&bound)?;
+                if let Some(mode) = mode {
+                    fs::set_permissions(&bound, fs::Permissions::from_mode(*mode))?;
+                }
+                fs::rename(&bound, path)?;
+
+                Ok(Listener::Unix {
+                    listener,
+                    path: Some(path.clone()),
+                })
+            }
+            ListenerConfig::Systemd => {
+                // Checking LISTEN_PID keeps child processes, which inherit the variables,
+                // from claiming the socket, so they are left set rather than removed while
+                // other threads may be reading the environment
+                let listen_pid = env::var("LISTEN_PID").ok();
+                let listen_fds = env::var("LISTEN_FDS").ok();
+                check_listen_fds(std::process::id(), listen_pid, listen_fds)?;
+
+                if SYSTEMD_SOCKET_TAKEN.swap(true, Ordering::SeqCst) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "the socket passed by systemd has already been taken",
+                    ));
+                }
+
+                // Safety: systemd passes the socket at this descriptor, which nothing else in
+                // the process owns, and the flag above ensures it is only taken once
+                unsafe { Self::from_raw_fd(SD_LISTEN_FDS_START) }
+            }
+        }
+    }
+
+    /// Takes ownership of an inherited listening socket, which may be TCP or Unix.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open socket that nothing else owns.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Self> {
+        // Getting the address fails if the socket is of another family
+        let listener = std::net::TcpListener::from_raw_fd(fd);
+        if listener.local_addr().is_ok() {
+            listener.set_nonblocking(true)?;
+            return Ok(Listener::Tcp(TcpListener::from_std(listener)?));
+        }
+
+        let listener = unix::UnixListener::from_raw_fd(listener.into_raw_fd());
+        let path = listener.local_addr()?.as_pathname().map(Path::to_path_buf);
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix {
+            listener: UnixListener::from_std(listener)?,
+            path,
+        })
+    }
+
+    /// Accepts a connection, along with the client's address if it has one. Clients of
+    /// Unix sockets do not.
+    pub async fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Some(address)))
+            }
+            Listener::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::Unix(stream), None))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => write!(f, "{address}"),
+                Err(_) => write!(f, "a TCP socket"),
+            },
+            Listener::Unix { path, .. } => match path {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "an unnamed Unix socket"),
+            },
+        }
+    }
+}
+
+/// A connection accepted by a [`Listener`]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Removes a socket left behind by an earlier run, which would otherwise stop binding.
+/// Anything else at `path` is left for binding to fail on.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Checks that systemd passed a single socket to this process
+fn check_listen_fds(
+    pid: u32,
+    listen_pid: Option<String>,
+    listen_fds: Option<String>,
+) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+
+    let listen_pid = listen_pid.ok_or_else(|| invalid("LISTEN_PID is not set".to_string()))?;
+    if listen_pid.parse() != Ok(pid) {
+        return Err(invalid(format!(
+            "LISTEN_PID is {listen_pid}, but this process is {pid}"
+        )));
+    }
+
+    let listen_fds = listen_fds.ok_or_else(|| invalid("LISTEN_FDS is not set".to_string()))?;
+    match listen_fds.parse::<u32>() {
+        Ok(1) => Ok(()),
+        _ => Err(invalid(format!(
+            "LISTEN_FDS is {listen_fds}, but exactly one socket is expected"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "0.0.0.0:3000".parse::<ListenerConfig>().unwrap(),
+            ListenerConfig::Tcp("0.0.0.0:3000".parse().unwrap())
+        );
+        assert_eq!(
+            "tcp:[::1]:3000".parse::<ListenerConfig>().unwrap(),
+            ListenerConfig::Tcp("[::1]:3000".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:/run/todos.sock".parse::<ListenerConfig>().unwrap(),
+            ListenerConfig::Unix {
+                path: "/run/todos.sock".into(),
+                mode: None
+            }
+        );
+        assert_eq!(
+            "systemd".parse::<ListenerConfig>().unwrap(),
+            ListenerConfig::Systemd
+        );
+
+        assert!("unix:".parse::<ListenerConfig>().is_err());
+        assert!("localhost".parse::<ListenerConfig>().is_err());
+    }
+
+    #[test]
+    fn test_check_listen_fds() {
+        let some = |value: &str| Some(value.to_string());
+
+        assert!(check_listen_fds(42, some("42"), some("1")).is_ok());
+
+        assert!(check_listen_fds(42, None, None).is_err());
+        assert!(check_listen_fds(42, some("43"), some("1")).is_err());
+        assert!(check_listen_fds(42, some("42"), None).is_err());
+        assert!(check_listen_fds(42, some("42"), some("0")).is_err());
+        assert!(check_listen_fds(42, some("42"), some("2")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_from_raw_fd() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let listener = unsafe { Listener::from_raw_fd(tcp.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Tcp(_)));
+        assert_eq!(listener.to_string(), address.to_string());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("todos.sock");
+        let unix = unix::UnixListener::bind(&path).unwrap();
+        let listener = unsafe { Listener::from_raw_fd(unix.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Unix { .. }));
+        assert_eq!(listener.to_string(), format!("unix:{}", path.display()));
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("todos.sock");
+        // Left by an earlier run
+        unix::UnixListener::bind(&path).unwrap();
+
+        let listener = Listener::bind(&ListenerConfig::Unix {
+            path: path.clone(),
+            mode: Some(0o600),
+        })
+        .await
+        .unwrap();
+        assert_eq!(listener.to_string(), format!("unix:{}", path.display()));
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // The directory it was bound in is gone
+        let entries: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+
+        let (connected, accepted) = tokio::join!(UnixStream::connect(&path), listener.accept());
+        connected.unwrap();
+        assert!(accepted.unwrap().1.is_none());
+    }
+}
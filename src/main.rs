@@ -1,18 +1,105 @@
-use std::env;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
-use axum_sqlx_mockall_todos::{app, db::SqliteTodoProvider, SqliteAppState};
-use sqlx::SqlitePool;
-use tower_http::trace::TraceLayer;
+use axum_sqlx_mockall_todos::{
+    admin::{self, MigrationState},
+    app,
+    body_limit::BodyLimitConfig,
+    cache::{CacheConfig, CacheLayer},
+    changes::ChangeFeed,
+    config::{AppConfig, CompressionConfig},
+    cors::CorsConfig,
+    db::{
+        DatabaseConfig, SqliteBackupProvider, SqlitePools, SqliteTodoProvider,
+        SqliteWebhookProvider,
+    },
+    layer::ProviderBuilder,
+    listener::{Listener, ListenerConfig},
+    provider::{ProviderError, TodoProvider},
+    rate_limit::{Budget, RateLimitConfig, RateLimiter},
+    resilience::{ResilienceConfig, ResilienceLayer},
+    server::{self, TlsConfig},
+    snapshots::{self, SnapshotConfig},
+    webhooks::{self, WebhookConfig},
+    SqliteAppState,
+};
+use clap::{Parser, Subcommand};
+use tokio::net::TcpListener;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+/// Where to listen if `LISTEN_ADDR` is not set
+const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:3000";
+/// How long todos stay in the trash if `TRASH_RETENTION_SECS` is not set
+const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Recorded as the actor in the history of todos purged from the trash
+const TRASH_PURGE_ACTOR: &str = "system";
+/// How often certificates are checked for changes if `TLS_RELOAD_SECS` is not set
+const DEFAULT_TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+/// How long clients have to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often snapshots are taken if `SNAPSHOT_INTERVAL_SECS` is not set
+const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How many snapshots are kept if `SNAPSHOT_RETAIN` is not set
+const DEFAULT_SNAPSHOT_RETAIN: usize = 7;
+/// How often the trash is checked for todos past their retention period
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Parser)]
+#[command(about = "A todo server, and tools for looking after its database")]
+struct Cli {
+    /// What to do, which is to serve if not given
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the API, first applying pending migrations
+    Serve {
+        /// Serve without applying migrations, such as when they are applied separately
+        #[arg(long)]
+        skip_migrations: bool,
+    },
+    /// Apply pending migrations
+    #[command(args_conflicts_with_subcommands = true)]
+    Migrate {
+        /// List the migrations that would be applied, without applying them
+        #[arg(long)]
+        dry_run: bool,
+        #[command(subcommand)]
+        command: Option<MigrateCommand>,
+    },
+    /// Add todos with fake data, which is the same every time
+    Seed {
+        #[arg(long, default_value_t = 100)]
+        count: usize,
+    },
+    /// Write a snapshot of the database, which can be done while serving
+    Backup { path: PathBuf },
+    /// Replace the database with a backup, which must be done while not serving
+    Restore { path: PathBuf },
+}
+
+#[derive(Subcommand)]
+enum MigrateCommand {
+    /// List migrations and whether they have been applied
+    Status,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
                 // axum logs rejections from built-in extractors with the `axum::rejection`
                 // target, at `TRACE` level. `axum::rejection=trace` enables showing those events
-                "rust_playground=debug,tower_http=debug,axum::rejection=trace".into()
+                "axum_sqlx_mockall_todos=debug,tower_http=debug,axum::rejection=trace".into()
             }),
         )
         .with(tracing_subscriber::fmt::layer())
@@ -22,20 +109,339 @@ async fn main() -> anyhow::Result<()> {
     let _ = dotenvy::dotenv();
 
     let db_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let pool = SqlitePool::connect(&db_url).await?;
 
-    // Run migrations
-    sqlx::migrate!().run(&pool).await?;
+    let command = cli.command.unwrap_or(Command::Serve {
+        skip_migrations: false,
+    });
+    match command {
+        Command::Serve { skip_migrations } => {
+            let pools = connect(&db_url).await?;
+            if !skip_migrations {
+                admin::MIGRATOR.run(&pools.writer).await?;
+            }
+            serve(pools).await
+        }
+        Command::Migrate {
+            command: Some(MigrateCommand::Status),
+            ..
+        } => {
+            let pools = connect(&db_url).await?;
+            for status in admin::migration_status(&pools.writer).await? {
+                let state = match status.state {
+                    MigrationState::Applied => "applied",
+                    MigrationState::Pending => "pending",
+                    MigrationState::Modified => "applied, but since modified",
+                    MigrationState::Unknown => "applied, but unknown to this version",
+                };
+                println!("{:>4} {:<24} {}", status.version, status.description, state);
+            }
+            Ok(())
+        }
+        Command::Migrate {
+            dry_run,
+            command: None,
+        } => {
+            let pools = connect(&db_url).await?;
+            let pending = admin::migrate(&pools.writer, dry_run).await?;
+            let verb = if dry_run { "Would apply" } else { "Applied" };
+            for status in &pending {
+                println!("{verb} {} {}", status.version, status.description);
+            }
+            if pending.is_empty() {
+                println!("No migrations are pending");
+            }
+            Ok(())
+        }
+        Command::Seed { count } => {
+            let pools = connect(&db_url).await?;
+            admin::MIGRATOR.run(&pools.writer).await?;
+            let todos = admin::seed(&SqliteTodoProvider::from(&pools), count)
+                .await
+                .map_err(|ProviderError(err)| err)?;
+            println!("Added {} todos", todos.len());
+            Ok(())
+        }
+        Command::Backup { path } => {
+            let pools = connect(&db_url).await?;
+            admin::backup(&pools.reader, &path).await?;
+            println!("Backed up to {}", path.display());
+            Ok(())
+        }
+        Command::Restore { path } => restore(&db_url, &path).await,
+    }
+}
+
+/// Opens the database. Migrations and seeding use the writer, and backups a reader.
+async fn connect(db_url: &str) -> anyhow::Result<SqlitePools> {
+    Ok(SqlitePools::connect(db_url, &database_config()?).await?)
+}
+
+/// Replaces the database with the backup at `path`. The database is not opened first, as it
+/// must not be in use.
+async fn restore(db_url: &str, path: &Path) -> anyhow::Result<()> {
+    let database = admin::database_path(db_url)?;
+    admin::restore(path, &database).await?;
+    println!("Restored {} from {}", database.display(), path.display());
+    Ok(())
+}
 
-    let provider = SqliteTodoProvider::from(&pool);
-    let state = SqliteAppState { provider };
+async fn serve(pools: SqlitePools) -> anyhow::Result<()> {
+    let trash_retention = match env::var("TRASH_RETENTION_SECS") {
+        Ok(secs) => Duration::from_secs(secs.parse()?),
+        Err(_) => DEFAULT_TRASH_RETENTION,
+    };
 
-    let app = app::router(state).layer(TraceLayer::new_for_http());
+    let rate_limit = rate_limit_config()?;
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    tracing::debug!("Listening at http://{}", listener.local_addr().unwrap());
+    // Cache hits skip retries and the circuit breaker, which only see calls to the database
+    let provider = ProviderBuilder::new()
+        .layer(CacheLayer::new(cache_config()?))
+        .layer(ResilienceLayer::new(resilience_config()?))
+        .build(SqliteTodoProvider::from(&pools));
+    tokio::spawn(purge_trash(provider.clone(), trash_retention));
 
-    axum::serve(listener, app).await.unwrap();
+    let changes = ChangeFeed::default();
+    let webhooks = SqliteWebhookProvider::from(&pools.writer);
+    tokio::spawn(webhooks::deliver(
+        webhooks.clone(),
+        WebhookConfig::default(),
+    ));
 
-    Ok(())
+    let backups = SqliteBackupProvider::from(&pools.reader);
+    if let Some(snapshots) = snapshot_config()? {
+        tokio::spawn(snapshots::schedule(backups.clone(), snapshots));
+    }
+
+    let state = SqliteAppState {
+        provider,
+        webhooks,
+        backups,
+        changes,
+        rate_limiter: RateLimiter::new(rate_limit),
+        config: AppConfig {
+            cors: cors_config()?,
+            body_limits: body_limit_config()?,
+            compression: compression_config()?,
+            admin_token: env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+        },
+    };
+
+    // gRPC is always served alongside everything else, and can also be served on its own
+    // port, where it is rate limited all the same
+    if let Ok(grpc_addr) = env::var("GRPC_ADDR") {
+        let listener = Listener::Tcp(TcpListener::bind(&grpc_addr).await?);
+        tracing::debug!("Serving gRPC at {}", listener);
+        tokio::spawn(server::serve(
+            listener,
+            app::grpc_router(state.clone()),
+            None,
+        ));
+    }
+
+    let app = app::router(state);
+
+    let listener = Listener::bind(&listener_config()?).await?;
+    let tls = tls_config()?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    tracing::debug!("Listening at {}://{}", scheme, listener);
+
+    // Clients are rate limited by their address if they have no API key
+    server::serve(listener, app, tls).await
+}
+
+/// Reads where to listen from `LISTEN_ADDR`, which is a TCP address, `unix:<path>` or
+/// `systemd`. The permissions of a Unix socket can be set in octal with `UNIX_SOCKET_MODE`.
+fn listener_config() -> anyhow::Result<ListenerConfig> {
+    let mut config: ListenerConfig = env::var("LISTEN_ADDR")
+        .as_deref()
+        .unwrap_or(DEFAULT_LISTEN_ADDR)
+        .parse()?;
+    if let (ListenerConfig::Unix { mode, .. }, Ok(octal)) =
+        (&mut config, env::var("UNIX_SOCKET_MODE"))
+    {
+        *mode = Some(u32::from_str_radix(&octal, 8)?);
+    }
+    Ok(config)
+}
+
+/// Reads the rate limits from `RATE_LIMIT_READS_PER_MINUTE` and
+/// `RATE_LIMIT_WRITES_PER_MINUTE`, and the comma-separated keys clients are limited by from
+/// `RATE_LIMIT_API_KEYS`
+fn rate_limit_config() -> anyhow::Result<RateLimitConfig> {
+    let mut rate_limit = RateLimitConfig::default();
+    if let Ok(limit) = env::var("RATE_LIMIT_READS_PER_MINUTE") {
+        rate_limit.read = Budget::per_minute(at_least_one("RATE_LIMIT_READS_PER_MINUTE", &limit)?);
+    }
+    if let Ok(limit) = env::var("RATE_LIMIT_WRITES_PER_MINUTE") {
+        rate_limit.write =
+            Budget::per_minute(at_least_one("RATE_LIMIT_WRITES_PER_MINUTE", &limit)?);
+    }
+    if let Ok(keys) = env::var("RATE_LIMIT_API_KEYS") {
+        rate_limit.api_keys = list(&keys).map(str::to_string).collect();
+    }
+    Ok(rate_limit)
+}
+
+/// Reads the TLS settings from `TLS_*` variables. HTTPS is served if `TLS_CERT_PATH` is set.
+fn tls_config() -> anyhow::Result<Option<TlsConfig>> {
+    let Ok(cert_path) = env::var("TLS_CERT_PATH") else {
+        return Ok(None);
+    };
+    let reload_interval = match env::var("TLS_RELOAD_SECS") {
+        Ok(secs) => Duration::from_secs(at_least_one("TLS_RELOAD_SECS", &secs)?),
+        Err(_) => DEFAULT_TLS_RELOAD_INTERVAL,
+    };
+
+    Ok(Some(TlsConfig {
+        cert_path: cert_path.into(),
+        key_path: env::var("TLS_KEY_PATH")
+            .map_err(|_| anyhow::anyhow!("TLS_KEY_PATH must be set with TLS_CERT_PATH"))?
+            .into(),
+        client_ca_path: env::var("TLS_CLIENT_CA_PATH").ok().map(Into::into),
+        reload_interval,
+        handshake_timeout: TLS_HANDSHAKE_TIMEOUT,
+    }))
+}
+
+/// Reads the connection settings from `DATABASE_BUSY_TIMEOUT_MS` and `DATABASE_MAX_READERS`
+fn database_config() -> anyhow::Result<DatabaseConfig> {
+    let mut database = DatabaseConfig::default();
+    if let Ok(millis) = env::var("DATABASE_BUSY_TIMEOUT_MS") {
+        database.busy_timeout = Duration::from_millis(millis.parse()?);
+    }
+    if let Ok(readers) = env::var("DATABASE_MAX_READERS") {
+        database.max_readers = readers.parse()?;
+    }
+    Ok(database)
+}
+
+/// Reads the cache settings from `CACHE_TTL_MS` and `CACHE_MAX_ENTRIES`
+fn cache_config() -> anyhow::Result<CacheConfig> {
+    let mut cache = CacheConfig::default();
+    if let Ok(millis) = env::var("CACHE_TTL_MS") {
+        cache.ttl = Duration::from_millis(millis.parse()?);
+    }
+    if let Ok(entries) = env::var("CACHE_MAX_ENTRIES") {
+        cache.max_entries = entries.parse()?;
+    }
+    Ok(cache)
+}
+
+/// Reads the retry and circuit breaker settings from `DATABASE_MAX_RETRIES`,
+/// `CIRCUIT_FAILURE_THRESHOLD` and `CIRCUIT_OPEN_SECS`
+fn resilience_config() -> anyhow::Result<ResilienceConfig> {
+    let mut resilience = ResilienceConfig::default();
+    if let Ok(retries) = env::var("DATABASE_MAX_RETRIES") {
+        resilience.max_retries = retries.parse()?;
+    }
+    if let Ok(failures) = env::var("CIRCUIT_FAILURE_THRESHOLD") {
+        resilience.failure_threshold = failures.parse()?;
+    }
+    if let Ok(secs) = env::var("CIRCUIT_OPEN_SECS") {
+        resilience.open_for = Duration::from_secs(secs.parse()?);
+    }
+    Ok(resilience)
+}
+
+/// Reads the snapshot settings from `SNAPSHOT_*` variables. Snapshots are taken if
+/// `SNAPSHOT_DIR` is set.
+fn snapshot_config() -> anyhow::Result<Option<SnapshotConfig>> {
+    let Ok(dir) = env::var("SNAPSHOT_DIR") else {
+        return Ok(None);
+    };
+    let interval = match env::var("SNAPSHOT_INTERVAL_SECS") {
+        Ok(secs) => Duration::from_secs(at_least_one("SNAPSHOT_INTERVAL_SECS", &secs)?),
+        Err(_) => DEFAULT_SNAPSHOT_INTERVAL,
+    };
+    let retain = match env::var("SNAPSHOT_RETAIN") {
+        Ok(retain) => at_least_one("SNAPSHOT_RETAIN", &retain)?,
+        Err(_) => DEFAULT_SNAPSHOT_RETAIN,
+    };
+
+    Ok(Some(SnapshotConfig {
+        dir: dir.into(),
+        interval,
+        retain,
+    }))
+}
+
+/// Reads the CORS policy from `CORS_*` variables, each list being comma-separated
+fn cors_config() -> anyhow::Result<CorsConfig> {
+    let mut cors = CorsConfig::default();
+    if let Ok(origins) = env::var("CORS_ALLOWED_ORIGINS") {
+        cors.allowed_origins = list(&origins).map(str::to_string).collect();
+    }
+    if let Ok(methods) = env::var("CORS_ALLOWED_METHODS") {
+        cors.allowed_methods = list(&methods).map(str::parse).collect::<Result<_, _>>()?;
+    }
+    if let Ok(headers) = env::var("CORS_ALLOWED_HEADERS") {
+        cors.allowed_headers = list(&headers).map(str::parse).collect::<Result<_, _>>()?;
+    }
+    if let Ok(credentials) = env::var("CORS_ALLOW_CREDENTIALS") {
+        cors.allow_credentials = credentials.parse()?;
+    }
+    if let Ok(secs) = env::var("CORS_MAX_AGE_SECS") {
+        cors.max_age = Some(Duration::from_secs(secs.parse()?));
+    }
+    cors.validate()?;
+    Ok(cors)
+}
+
+/// Reads body limits from `BODY_LIMIT_BYTES`, and limits for particular routes from
+/// `BODY_LIMITS` as comma-separated `<route>=<bytes>` pairs
+fn body_limit_config() -> anyhow::Result<BodyLimitConfig> {
+    let mut limits = BodyLimitConfig::default();
+    if let Ok(bytes) = env::var("BODY_LIMIT_BYTES") {
+        limits.default = bytes.parse()?;
+    }
+    if let Ok(routes) = env::var("BODY_LIMITS") {
+        for route in list(&routes) {
+            let (route, bytes) = route
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("body limit {route} is not <route>=<bytes>"))?;
+            limits.routes.insert(route.to_string(), bytes.parse()?);
+        }
+    }
+    Ok(limits)
+}
+
+fn compression_config() -> anyhow::Result<CompressionConfig> {
+    let mut compression = CompressionConfig::default();
+    if let Ok(bytes) = env::var("COMPRESSION_MIN_SIZE") {
+        compression.min_size = bytes.parse()?;
+    }
+    Ok(compression)
+}
+
+/// Parses the value of the variable `name`, which must be at least 1
+fn at_least_one<T>(name: &str, value: &str) -> anyhow::Result<T>
+where
+    T: FromStr + PartialOrd + From<u8>,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let value: T = value.parse()?;
+    anyhow::ensure!(value >= T::from(1), "{name} must be at least 1");
+    Ok(value)
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Periodically removes todos that have been in the trash longer than `retention`
+async fn purge_trash(provider: impl TodoProvider, retention: Duration) {
+    let mut interval = tokio::time::interval(TRASH_PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match provider.purge_trash(retention, TRASH_PURGE_ACTOR).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!("Purged {} todos from the trash", purged),
+            Err(ProviderError(err)) => tracing::error!("Failed to purge trash: {}", err),
+        }
+    }
 }
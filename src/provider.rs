@@ -1,19 +1,82 @@
+use std::{path::Path, time::Duration};
+
 use async_trait::async_trait;
 
-use crate::endpoints::Todo;
+use crate::{
+    endpoints::{
+        BatchMode, BatchOperation, BatchOutcome, DeadLetter, Todo, TodoRevision, TrashedTodo,
+        Webhook,
+    },
+    webhooks::WebhookDelivery,
+};
 
+/// Every method that changes a todo takes the `actor` responsible for the change, which is
+/// recorded in the todo's history.
 #[mockall::automock]
 #[async_trait]
 pub trait TodoProvider {
     async fn get_todos(&self) -> Result<Vec<Todo>, ProviderError>;
     async fn get_todo(&self, id: i64) -> Result<Option<Todo>, ProviderError>;
-    async fn add_todo(&self, description: &str) -> Result<Todo, ProviderError>;
+    async fn add_todo(&self, description: &str, actor: &str) -> Result<Todo, ProviderError>;
+    /// Returns `None` if there is no such todo.
     async fn update_todo(
         &self,
         id: i64,
         description: &str,
         done: bool,
-    ) -> Result<Todo, ProviderError>;
+        actor: &str,
+    ) -> Result<Option<Todo>, ProviderError>;
+    /// Moves a todo to the trash. Returns `None` if there is no such todo.
+    async fn delete_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError>;
+    async fn get_trash(&self) -> Result<Vec<TrashedTodo>, ProviderError>;
+    /// Takes a todo back out of the trash. Returns `None` if it is not in the trash.
+    async fn restore_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError>;
+    /// Permanently removes todos that have been in the trash for at least `retention`,
+    /// returning how many were removed.
+    async fn purge_trash(&self, retention: Duration, actor: &str) -> Result<u64, ProviderError>;
+    /// Returns every recorded change to a todo, oldest first.
+    async fn get_history(&self, id: i64) -> Result<Vec<TodoRevision>, ProviderError>;
+    /// Applies `operations` in order within a single transaction. Failures of individual
+    /// operations are reported in the outcome rather than as an error.
+    async fn apply_batch(
+        &self,
+        operations: Vec<BatchOperation>,
+        mode: BatchMode,
+        actor: &str,
+    ) -> Result<BatchOutcome, ProviderError>;
+}
+
+/// Stores webhook subscriptions and the deliveries to them that are still to be made.
+#[mockall::automock]
+#[async_trait]
+pub trait WebhookProvider {
+    async fn get_webhooks(&self) -> Result<Vec<Webhook>, ProviderError>;
+    async fn add_webhook(&self, url: &str, secret: &str) -> Result<Webhook, ProviderError>;
+    /// Removes a webhook along with its pending deliveries. Returns whether it existed.
+    async fn delete_webhook(&self, id: i64) -> Result<bool, ProviderError>;
+    /// Returns up to `limit` pending deliveries whose next attempt is due, oldest first.
+    async fn get_due_deliveries(&self, limit: i64) -> Result<Vec<WebhookDelivery>, ProviderError>;
+    /// Removes a delivery that has been made.
+    async fn mark_delivered(&self, id: i64) -> Result<(), ProviderError>;
+    /// Records a failed attempt. The delivery is retried after `retry_in`, or becomes a dead
+    /// letter if it is `None`.
+    async fn mark_failed(
+        &self,
+        id: i64,
+        error: &str,
+        retry_in: Option<Duration>,
+    ) -> Result<(), ProviderError>;
+    async fn get_dead_letters(&self) -> Result<Vec<DeadLetter>, ProviderError>;
+    /// Queues a dead letter to be delivered again. Returns whether there was such a dead letter.
+    async fn retry_dead_letter(&self, id: i64) -> Result<bool, ProviderError>;
+}
+
+/// Takes snapshots of the database while it is in use.
+#[mockall::automock]
+#[async_trait]
+pub trait BackupProvider {
+    /// Writes a consistent snapshot to `path`, which must not exist
+    async fn backup(&self, path: &Path) -> Result<(), ProviderError>;
 }
 
 pub struct ProviderError(pub anyhow::Error);
@@ -0,0 +1,359 @@
+//! Per-client rate limiting with token buckets. Clients are told about their budget in the
+//! `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` response headers.
+//!
+//! Clients are identified by the API key in an `X-Api-Key` or bearer `Authorization`
+//! header if it is one the server knows, or otherwise by their IP address, so that made up
+//! keys cannot be used for fresh budgets. Reads and writes have separate budgets, so that a
+//! client writing too much can still read.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    app::{AppError, AppState},
+    endpoints::constant_time_eq,
+};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// How many buckets are kept before the least recently used is dropped
+const MAX_BUCKETS: usize = 10_000;
+
+/// Allows `limit` requests in a burst, refilling at `limit` requests per `period`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Budget {
+    pub limit: u32,
+    pub period: Duration,
+}
+
+impl Budget {
+    pub fn per_minute(limit: u32) -> Self {
+        Budget {
+            limit,
+            period: Duration::from_secs(60),
+        }
+    }
+
+    /// How long it takes to refill `tokens`
+    fn refill_time(&self, tokens: f64) -> Duration {
+        self.period.mul_f64(tokens / f64::from(self.limit))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    pub read: Budget,
+    pub write: Budget,
+    /// Keys that clients are limited by, besides the admin token. Other keys are ignored.
+    pub api_keys: HashSet<String>,
+}
+
+impl RateLimitConfig {
+    pub fn budget(&self, class: Class) -> Budget {
+        match class {
+            Class::Read => self.read,
+            Class::Write => self.write,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            read: Budget::per_minute(600),
+            write: Budget::per_minute(60),
+            api_keys: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Class {
+    Read,
+    Write,
+}
+
+impl Class {
+    pub fn of(method: &Method) -> Self {
+        match *method {
+            Method::GET | Method::HEAD | Method::OPTIONS => Class::Read,
+            _ => Class::Write,
+        }
+    }
+}
+
+/// The outcome of checking a request against a client's budget
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// How long until the budget is full again
+    pub reset: Duration,
+    /// How long until another request is allowed, if this one was not
+    pub retry_after: Duration,
+}
+
+type BucketKey = (String, Class);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket was last used, as a key of [`Buckets::used`]
+    used: u64,
+}
+
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    /// The key of every bucket, least recently used first
+    used: BTreeMap<u64, BucketKey>,
+    uses: u64,
+}
+
+/// Shared between every request, keeping a bucket for each client and class of request.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    max_buckets: usize,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config: Arc::new(config),
+            max_buckets: MAX_BUCKETS,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Whether clients with `key` are limited by it rather than by their address
+    pub fn is_api_key(&self, key: &str) -> bool {
+        self.config.api_keys.contains(key)
+    }
+
+    pub fn check(&self, client: &str, class: Class) -> Decision {
+        self.check_at(client, class, Instant::now())
+    }
+
+    fn check_at(&self, client: &str, class: Class, now: Instant) -> Decision {
+        let budget = self.config.budget(class);
+        let capacity = f64::from(budget.limit);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let Buckets {
+            buckets,
+            used,
+            uses,
+        } = &mut *buckets;
+        *uses += 1;
+
+        let key = (client.to_string(), class);
+        if !buckets.contains_key(&key) {
+            // A bucket dropped early only lets its client start again with a full budget
+            while buckets.len() >= self.max_buckets {
+                let Some((_, oldest)) = used.pop_first() else {
+                    break;
+                };
+                buckets.remove(&oldest);
+            }
+        }
+
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            used: *uses,
+        });
+        used.remove(&bucket.used);
+        bucket.used = *uses;
+        used.insert(*uses, key);
+
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        let refilled = elapsed.as_secs_f64() / budget.period.as_secs_f64() * capacity;
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Decision {
+            allowed,
+            limit: budget.limit,
+            remaining: bucket.tokens as u32,
+            reset: budget.refill_time(capacity - bucket.tokens),
+            retry_after: if allowed {
+                Duration::ZERO
+            } else {
+                budget.refill_time(1.0 - bucket.tokens)
+            },
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(RateLimitConfig::default())
+    }
+}
+
+/// Middleware rejecting requests from clients over their budget with a 429
+pub async fn limit<A: AppState>(
+    State(state): State<A>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let admin_token = state.config().admin_token.as_deref();
+    let key = api_key(request.headers()).filter(|key| {
+        state.rate_limiter().is_api_key(key)
+            || admin_token.is_some_and(|token| constant_time_eq(token, key))
+    });
+    let client = match key {
+        Some(key) => format!("key:{key}"),
+        None => match connect_info {
+            Some(ConnectInfo(address)) => format!("ip:{}", address.ip()),
+            // Only for clients of Unix sockets, or when not served with connection info, in
+            // which case every client shares a budget
+            None => "unknown".to_string(),
+        },
+    };
+
+    let decision = state
+        .rate_limiter()
+        .check(&client, Class::of(request.method()));
+
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        AppError::TooManyRequests {
+            retry_after: decision.retry_after,
+        }
+        .into_response()
+    };
+
+    let headers = response.headers_mut();
+    headers.insert("ratelimit-limit", decision.limit.into());
+    headers.insert("ratelimit-remaining", decision.remaining.into());
+    headers.insert("ratelimit-reset", seconds(decision.reset));
+
+    response
+}
+
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    let key = match headers.get(API_KEY_HEADER) {
+        Some(key) => key.to_str().ok()?,
+        None => headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?,
+    };
+    Some(key.trim()).filter(|key| !key.is_empty())
+}
+
+/// A header value of whole seconds, rounded up
+pub fn seconds(duration: Duration) -> HeaderValue {
+    whole_seconds(duration).into()
+}
+
+/// The duration in whole seconds, rounded up
+pub fn whole_seconds(duration: Duration) -> u64 {
+    let mut secs = duration.as_secs();
+    if duration.subsec_nanos() > 0 {
+        secs += 1;
+    }
+    secs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            read: Budget::per_minute(60),
+            write: Budget::per_minute(2),
+            ..RateLimitConfig::default()
+        });
+        let start = Instant::now();
+
+        let first = limiter.check_at("alice", Class::Write, start);
+        let second = limiter.check_at("alice", Class::Write, start);
+        let third = limiter.check_at("alice", Class::Write, start);
+
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+        assert_eq!(first.reset, Duration::from_secs(30));
+        assert!(second.allowed);
+        assert_eq!(second.remaining, 0);
+        assert_eq!(
+            third,
+            Decision {
+                allowed: false,
+                limit: 2,
+                remaining: 0,
+                reset: Duration::from_secs(60),
+                retry_after: Duration::from_secs(30),
+            }
+        );
+
+        // Reads and other clients have their own budgets
+        assert!(limiter.check_at("alice", Class::Read, start).allowed);
+        assert!(limiter.check_at("bob", Class::Write, start).allowed);
+
+        // A token is refilled every 30 seconds
+        let later = start + Duration::from_secs(30);
+        assert!(limiter.check_at("alice", Class::Write, later).allowed);
+        assert!(!limiter.check_at("alice", Class::Write, later).allowed);
+    }
+
+    #[test]
+    fn test_max_buckets() {
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            read: Budget::per_minute(1),
+            ..RateLimitConfig::default()
+        });
+        limiter.max_buckets = 2;
+        let now = Instant::now();
+
+        assert!(limiter.check_at("alice", Class::Read, now).allowed);
+        assert!(limiter.check_at("bob", Class::Read, now).allowed);
+        assert!(!limiter.check_at("alice", Class::Read, now).allowed);
+
+        // Bob's bucket is the least recently used, so is dropped for Carol's
+        assert!(limiter.check_at("carol", Class::Read, now).allowed);
+        assert!(!limiter.check_at("alice", Class::Read, now).allowed);
+        assert!(limiter.check_at("bob", Class::Read, now).allowed);
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.buckets.len(), 2);
+        assert_eq!(buckets.used.len(), 2);
+    }
+
+    #[test]
+    fn test_api_key() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(api_key(&headers), None);
+
+        headers.insert(header::AUTHORIZATION, "Bearer abc".parse().unwrap());
+        assert_eq!(api_key(&headers), Some("abc"));
+
+        headers.insert(API_KEY_HEADER, "def".parse().unwrap());
+        assert_eq!(api_key(&headers), Some("def"));
+    }
+}
@@ -0,0 +1,70 @@
+//! Request IDs, taken from the `X-Request-Id` header or generated if there is none. The ID
+//! is echoed in the response, recorded on the request's tracing span, and included in
+//! error bodies so that a reported error can be found in the logs.
+
+use axum::{
+    extract::Request,
+    http::{HeaderName, Request as HttpRequest},
+    middleware::Next,
+    response::Response,
+};
+use tower_http::{
+    classify::{ServerErrorsAsFailures, SharedClassifier},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{MakeSpan, TraceLayer},
+};
+use tracing::Span;
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request being handled, if called while handling one
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+pub fn set_layer() -> SetRequestIdLayer<MakeRequestUuid> {
+    SetRequestIdLayer::new(HEADER, MakeRequestUuid)
+}
+
+pub fn propagate_layer() -> PropagateRequestIdLayer {
+    PropagateRequestIdLayer::new(HEADER)
+}
+
+/// Traces requests in a span recording their ID, which must already have been set. The span
+/// is at info level so that the ID is on every event logged under the usual filters.
+pub fn trace_layer() -> TraceLayer<SharedClassifier<ServerErrorsAsFailures>, RequestSpan> {
+    TraceLayer::new_for_http().make_span_with(RequestSpan)
+}
+
+#[derive(Clone, Copy)]
+pub struct RequestSpan;
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &HttpRequest<B>) -> Span {
+        tracing::info_span!(
+            "request",
+            request_id = request_id(request),
+            method = %request.method(),
+            uri = %request.uri(),
+            version = ?request.version(),
+        )
+    }
+}
+
+/// Middleware making the request's ID available through [`current`] while it is handled
+pub async fn scope(request: Request, next: Next) -> Response {
+    let id = request_id(&request).to_string();
+    REQUEST_ID.scope(id, next.run(request)).await
+}
+
+fn request_id<B>(request: &HttpRequest<B>) -> &str {
+    request
+        .headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,620 @@
+//! Retries and a circuit breaker for provider calls, so that brief contention such as
+//! `database is locked` does not fail requests, and a database that keeps failing is given
+//! time to recover rather than being retried by every request.
+//!
+//! Reads are retried on any error. Writes are only retried when the database was locked or
+//! no connection was free, which happen before anything is written. Errors classified as
+//! transient by [`is_transient`] count towards opening the circuit. While it is open, calls
+//! fail at once with [`Unavailable`].
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rand::Rng;
+use tokio::time::Instant;
+
+use crate::{
+    endpoints::{BatchMode, BatchOperation, BatchOutcome, Todo, TodoRevision, TrashedTodo},
+    layer::ProviderLayer,
+    provider::{ProviderError, TodoProvider},
+};
+
+/// SQLite's primary result codes for a database locked by another connection
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResilienceConfig {
+    /// How many times a call is retried after failing
+    pub max_retries: u32,
+    /// The delay before the first retry, which doubles for each retry after
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// How many failures in a row open the circuit
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a call is let through to try again
+    pub open_for: Duration,
+}
+
+impl Default for ResilienceConfig {
+    fn default() -> Self {
+        ResilienceConfig {
+            max_retries: 3,
+            base_delay: Duration::from_millis(20),
+            max_delay: Duration::from_secs(1),
+            failure_threshold: 5,
+            open_for: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ResilienceConfig {
+    /// The delay before retry number `retry`, counting from zero, with jitter so that
+    /// callers who failed together do not retry together
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// The error within a [`ProviderError`] while the circuit is open
+#[derive(Debug)]
+pub struct Unavailable {
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the database is unavailable after repeated failures")
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// Whether the error is one that retrying may get past, such as the database being locked
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    is_unwritten(err) || matches!(err.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::Io(_)))
+}
+
+/// Whether the error is transient and certain to have been raised before anything was
+/// written. An I/O error may come after a write was committed, so is not.
+fn is_unwritten(err: &anyhow::Error) -> bool {
+    let Some(err) = err.downcast_ref::<sqlx::Error>() else {
+        return false;
+    };
+    match err {
+        sqlx::Error::Database(err) => err
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            // Extended result codes keep the primary code in their low byte
+            .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED)),
+        sqlx::Error::PoolTimedOut => true,
+        _ => false,
+    }
+}
+
+/// Retries calls to the provider it wraps, and stops calling it while it keeps failing.
+/// Clones share the circuit.
+#[derive(Clone)]
+pub struct ResilientTodoProvider<P> {
+    inner: P,
+    config: ResilienceConfig,
+    breaker: Arc<Mutex<Breaker>>,
+}
+
+struct Breaker {
+    circuit: Circuit,
+    /// Incremented whenever the circuit changes state, so that the results of calls let
+    /// through in an earlier state are ignored
+    generation: u64,
+}
+
+impl Breaker {
+    fn transition(&mut self, circuit: Circuit) {
+        self.circuit = circuit;
+        self.generation += 1;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Circuit {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// Letting one call through to find out whether the provider has recovered
+    HalfOpen,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Retry {
+    Always,
+    /// Only if nothing can have been written
+    Unwritten,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Success,
+    /// A transient failure, which counts towards opening the circuit
+    Transient,
+    /// Any other failure, which says nothing about whether the provider is healthy
+    Other,
+}
+
+impl<P> ResilientTodoProvider<P> {
+    pub fn new(inner: P, config: ResilienceConfig) -> Self {
+        ResilientTodoProvider {
+            inner,
+            config,
+            breaker: Arc::new(Mutex::new(Breaker {
+                circuit: Circuit::Closed { failures: 0 },
+                generation: 0,
+            })),
+        }
+    }
+
+    async fn call<T, F, Fut>(&self, retry: Retry, f: F) -> Result<T, ProviderError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ProviderError>>,
+    {
+        let mut permit = self.permit()?;
+        let mut retries = 0;
+        loop {
+            let err = match f().await {
+                Ok(value) => {
+                    permit.record(Outcome::Success);
+                    return Ok(value);
+                }
+                Err(ProviderError(err)) => err,
+            };
+
+            let transient = is_transient(&err);
+            let retryable = match retry {
+                Retry::Always => true,
+                Retry::Unwritten => is_unwritten(&err),
+            };
+            if retries < self.config.max_retries && retryable {
+                tracing::debug!("Retrying after error: {}", err);
+                tokio::time::sleep(self.config.backoff(retries)).await;
+                retries += 1;
+                continue;
+            }
+
+            permit.record(if transient {
+                Outcome::Transient
+            } else {
+                Outcome::Other
+            });
+            return Err(ProviderError(err));
+        }
+    }
+
+    fn permit(&self) -> Result<Permit<'_, P>, ProviderError> {
+        let mut breaker = self.breaker.lock().unwrap();
+        let now = Instant::now();
+        match breaker.circuit {
+            Circuit::Closed { .. } => {}
+            Circuit::Open { until } if until <= now => {
+                breaker.transition(Circuit::HalfOpen);
+                return Ok(Permit {
+                    provider: self,
+                    generation: breaker.generation,
+                    trial: true,
+                    recorded: false,
+                });
+            }
+            Circuit::Open { until } => {
+                return Err(ProviderError(
+                    Unavailable {
+                        retry_after: until - now,
+                    }
+                    .into(),
+                ))
+            }
+            Circuit::HalfOpen => {
+                return Err(ProviderError(
+                    Unavailable {
+                        retry_after: self.config.base_delay,
+                    }
+                    .into(),
+                ))
+            }
+        }
+
+        Ok(Permit {
+            provider: self,
+            generation: breaker.generation,
+            trial: false,
+            recorded: false,
+        })
+    }
+}
+
+/// Wraps providers in a [`ResilientTodoProvider`]
+#[derive(Clone, Copy, Debug)]
+pub struct ResilienceLayer {
+    config: ResilienceConfig,
+}
+
+impl ResilienceLayer {
+    pub fn new(config: ResilienceConfig) -> Self {
+        ResilienceLayer { config }
+    }
+}
+
+impl<P> ProviderLayer<P> for ResilienceLayer {
+    type Provider = ResilientTodoProvider<P>;
+
+    fn layer(&self, inner: P) -> Self::Provider {
+        ResilientTodoProvider::new(inner, self.config)
+    }
+}
+
+/// Allows a call while the circuit is closed, or as the trial call while it is half open
+struct Permit<'a, P> {
+    provider: &'a ResilientTodoProvider<P>,
+    /// The generation of the circuit when the call was let through
+    generation: u64,
+    trial: bool,
+    recorded: bool,
+}
+
+impl<P> Permit<'_, P> {
+    fn record(&mut self, outcome: Outcome) {
+        self.recorded = true;
+        let config = &self.provider.config;
+        let mut breaker = self.provider.breaker.lock().unwrap();
+        // The circuit has changed since, such as by opening while this call was slow
+        if breaker.generation != self.generation {
+            return;
+        }
+
+        let open = Circuit::Open {
+            until: Instant::now() + config.open_for,
+        };
+        match (breaker.circuit, outcome) {
+            (Circuit::Closed { .. }, Outcome::Success) => {
+                breaker.circuit = Circuit::Closed { failures: 0 };
+            }
+            (Circuit::Closed { failures }, Outcome::Transient)
+                if failures + 1 < config.failure_threshold =>
+            {
+                breaker.circuit = Circuit::Closed {
+                    failures: failures + 1,
+                };
+            }
+            (Circuit::Closed { .. }, Outcome::Transient)
+            | (Circuit::HalfOpen, Outcome::Transient) => {
+                tracing::warn!(
+                    "Opening circuit for {:?} after repeated failures",
+                    config.open_for
+                );
+                breaker.transition(open);
+            }
+            (Circuit::HalfOpen, Outcome::Success) => {
+                breaker.transition(Circuit::Closed { failures: 0 });
+            }
+            // The trial proved nothing, so the next call is the trial
+            (Circuit::HalfOpen, Outcome::Other) => breaker.transition(Circuit::Open {
+                until: Instant::now(),
+            }),
+            (Circuit::Closed { .. }, Outcome::Other) | (Circuit::Open { .. }, _) => {}
+        }
+    }
+}
+
+impl<P> Drop for Permit<'_, P> {
+    fn drop(&mut self) {
+        // A trial call that was cancelled leaves the next call to be the trial
+        if self.trial && !self.recorded {
+            let mut breaker = self.provider.breaker.lock().unwrap();
+            if breaker.generation == self.generation {
+                breaker.transition(Circuit::Open {
+                    until: Instant::now(),
+                });
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<P: TodoProvider + Send + Sync> TodoProvider for ResilientTodoProvider<P> {
+    async fn get_todos(&self) -> Result<Vec<Todo>, ProviderError> {
+        self.call(Retry::Always, || self.inner.get_todos()).await
+    }
+
+    async fn get_todo(&self, id: i64) -> Result<Option<Todo>, ProviderError> {
+        self.call(Retry::Always, || self.inner.get_todo(id)).await
+    }
+
+    async fn add_todo(&self, description: &str, actor: &str) -> Result<Todo, ProviderError> {
+        self.call(Retry::Unwritten, || self.inner.add_todo(description, actor))
+            .await
+    }
+
+    async fn update_todo(
+        &self,
+        id: i64,
+        description: &str,
+        done: bool,
+        actor: &str,
+    ) -> Result<Option<Todo>, ProviderError> {
+        self.call(Retry::Unwritten, || {
+            self.inner.update_todo(id, description, done, actor)
+        })
+        .await
+    }
+
+    async fn delete_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError> {
+        self.call(Retry::Unwritten, || self.inner.delete_todo(id, actor))
+            .await
+    }
+
+    async fn get_trash(&self) -> Result<Vec<TrashedTodo>, ProviderError> {
+        self.call(Retry::Always, || self.inner.get_trash()).await
+    }
+
+    async fn restore_todo(&self, id: i64, actor: &str) -> Result<Option<Todo>, ProviderError> {
+        self.call(Retry::Unwritten, || self.inner.restore_todo(id, actor))
+            .await
+    }
+
+    async fn purge_trash(&self, retention: Duration, actor: &str) -> Result<u64, ProviderError> {
+        self.call(Retry::Unwritten, || {
+            self.inner.purge_trash(retention, actor)
+        })
+        .await
+    }
+
+    async fn get_history(&self, id: i64) -> Result<Vec<TodoRevision>, ProviderError> {
+        self.call(Retry::Always, || self.inner.get_history(id))
+            .await
+    }
+
+    async fn apply_batch(
+        &self,
+        operations: Vec<BatchOperation>,
+        mode: BatchMode,
+        actor: &str,
+    ) -> Result<BatchOutcome, ProviderError> {
+        self.call(Retry::Unwritten, || {
+            self.inner.apply_batch(operations.clone(), mode, actor)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::MockTodoProvider;
+
+    fn config() -> ResilienceConfig {
+        ResilienceConfig {
+            max_retries: 2,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(100),
+            failure_threshold: 2,
+            open_for: Duration::from_secs(10),
+        }
+    }
+
+    fn transient() -> ProviderError {
+        ProviderError(sqlx::Error::PoolTimedOut.into())
+    }
+
+    fn todo() -> Todo {
+        Todo {
+            id: 1,
+            description: "test".to_string(),
+            done: false,
+        }
+    }
+
+    fn unavailable(result: Result<Vec<Todo>, ProviderError>) -> Option<Duration> {
+        match result {
+            Err(ProviderError(err)) => err
+                .downcast_ref::<Unavailable>()
+                .map(|unavailable| unavailable.retry_after),
+            Ok(_) => None,
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let config = config();
+        for (retry, max) in [(0, 10), (1, 20), (2, 40), (3, 80), (4, 100), (20, 100)] {
+            let delay = config.backoff(retry);
+            let max = Duration::from_millis(max);
+            assert!(delay >= max / 2 && delay <= max, "{retry}: {delay:?}");
+        }
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(&sqlx::Error::PoolTimedOut.into()));
+        assert!(!is_transient(&sqlx::Error::RowNotFound.into()));
+        assert!(!is_transient(&anyhow::anyhow!("something else")));
+
+        let io = || sqlx::Error::Io(std::io::ErrorKind::BrokenPipe.into()).into();
+        assert!(is_transient(&io()));
+        assert!(!is_unwritten(&io()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reads_are_retried() {
+        let mut inner = MockTodoProvider::new();
+        inner
+            .expect_get_todos()
+            .times(1)
+            .returning(|| Err(transient()));
+        inner
+            .expect_get_todos()
+            .times(1)
+            .returning(|| Err(ProviderError(anyhow::anyhow!("not transient"))));
+        inner
+            .expect_get_todos()
+            .times(1)
+            .returning(|| Ok(vec![todo()]));
+
+        let provider = ResilientTodoProvider::new(inner, config());
+        assert_eq!(provider.get_todos().await.ok().unwrap().len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_writes_are_retried_on_transient_errors() {
+        let mut inner = MockTodoProvider::new();
+        inner
+            .expect_add_todo()
+            .times(1)
+            .returning(|_, _| Err(transient()));
+        inner
+            .expect_add_todo()
+            .times(1)
+            .returning(|_, _| Ok(todo()));
+        inner
+            .expect_update_todo()
+            .times(1)
+            .returning(|_, _, _, _| Err(ProviderError(sqlx::Error::RowNotFound.into())));
+        inner.expect_apply_batch().times(1).returning(|_, _, _| {
+            Err(ProviderError(
+                sqlx::Error::Io(std::io::ErrorKind::BrokenPipe.into()).into(),
+            ))
+        });
+
+        let provider = ResilientTodoProvider::new(inner, config());
+        assert!(provider.add_todo("test", "test").await.is_ok());
+        // The writes may have happened, so are not retried
+        assert!(provider.update_todo(1, "test", true, "test").await.is_err());
+        assert!(provider
+            .apply_batch(vec![], BatchMode::Atomic, "test")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_breaker() {
+        let mut inner = MockTodoProvider::new();
+        // Two calls, each tried three times
+        inner
+            .expect_get_todos()
+            .times(6)
+            .returning(|| Err(transient()));
+
+        let provider = ResilientTodoProvider::new(inner, config());
+        for _ in 0..2 {
+            assert!(provider.get_todos().await.is_err());
+        }
+
+        // The inner provider is no longer called
+        assert_eq!(
+            unavailable(provider.get_todos().await),
+            Some(Duration::from_secs(10))
+        );
+        tokio::time::advance(Duration::from_secs(4)).await;
+        assert_eq!(
+            unavailable(provider.get_todos().await),
+            Some(Duration::from_secs(6))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_circuit_recovers() {
+        let mut inner = MockTodoProvider::new();
+        inner
+            .expect_get_todos()
+            .times(6)
+            .returning(|| Err(transient()));
+        // The trial call after the circuit has been open fails, so it opens again
+        inner
+            .expect_get_todos()
+            .times(3)
+            .returning(|| Err(transient()));
+        inner.expect_get_todos().times(2).returning(|| Ok(vec![]));
+
+        let provider = ResilientTodoProvider::new(inner, config());
+        for _ in 0..2 {
+            assert!(provider.get_todos().await.is_err());
+        }
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(unavailable(provider.get_todos().await), None);
+        assert!(unavailable(provider.get_todos().await).is_some());
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(provider.get_todos().await.is_ok());
+        assert!(provider.get_todos().await.is_ok());
+    }
+
+    fn circuit<P>(provider: &ResilientTodoProvider<P>) -> Circuit {
+        provider.breaker.lock().unwrap().circuit
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_other_failures_do_not_count() {
+        let mut inner = MockTodoProvider::new();
+        // Two writes, each tried three times, around one that fails for another reason
+        inner
+            .expect_add_todo()
+            .times(6)
+            .returning(|_, _| Err(transient()));
+        inner
+            .expect_update_todo()
+            .times(1)
+            .returning(|_, _, _, _| Err(ProviderError(sqlx::Error::RowNotFound.into())));
+
+        let provider = ResilientTodoProvider::new(inner, config());
+        assert!(provider.add_todo("test", "test").await.is_err());
+        assert!(provider.update_todo(1, "test", true, "test").await.is_err());
+        assert_eq!(circuit(&provider), Circuit::Closed { failures: 1 });
+
+        assert!(provider.add_todo("test", "test").await.is_err());
+        assert!(matches!(circuit(&provider), Circuit::Open { .. }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_trial_other_failure() {
+        let provider = ResilientTodoProvider::new(MockTodoProvider::new(), config());
+        for _ in 0..2 {
+            provider.permit().ok().unwrap().record(Outcome::Transient);
+        }
+        tokio::time::advance(Duration::from_secs(10)).await;
+
+        // A trial failing for another reason neither closes the circuit nor keeps it open
+        let mut trial = provider.permit().ok().unwrap();
+        assert_eq!(circuit(&provider), Circuit::HalfOpen);
+        trial.record(Outcome::Other);
+        drop(trial);
+        assert!(provider.permit().ok().unwrap().trial);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_late_results_are_ignored() {
+        let provider = ResilientTodoProvider::new(MockTodoProvider::new(), config());
+        let mut late_success = provider.permit().ok().unwrap();
+        let mut late_failure = provider.permit().ok().unwrap();
+        for _ in 0..2 {
+            provider.permit().ok().unwrap().record(Outcome::Transient);
+        }
+        let open = circuit(&provider);
+        assert!(matches!(open, Circuit::Open { .. }));
+
+        // Calls let through before the circuit opened neither close nor reopen it
+        late_success.record(Outcome::Success);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        late_failure.record(Outcome::Transient);
+        assert_eq!(circuit(&provider), open);
+    }
+}
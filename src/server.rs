@@ -0,0 +1,211 @@
+//! Serving the router on a [`Listener`], over HTTP or HTTPS. Certificates are reloaded when
+//! their files change, so that renewed certificates are picked up without a restart, and
+//! clients can be required to present certificates of their own.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, Weak},
+    time::Duration,
+};
+
+use anyhow::Context;
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+
+use crate::listener::Listener;
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// A PEM file holding the certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// A PEM file holding the private key
+    pub key_path: PathBuf,
+    /// A PEM file holding the CA certificates that client certificates must be signed by.
+    /// Clients do not need certificates if this is not given.
+    pub client_ca_path: Option<PathBuf>,
+    /// How often to check the files for changes. Must not be zero.
+    pub reload_interval: Duration,
+    /// How long clients have to complete the TLS handshake before they are disconnected
+    pub handshake_timeout: Duration,
+}
+
+/// Serves `app`, over HTTPS if `tls` is given. The addresses of TCP clients are available
+/// to handlers as [`ConnectInfo<SocketAddr>`]. Fails only if the certificates cannot be
+/// loaded at first.
+pub async fn serve(listener: Listener, app: Router, tls: Option<TlsConfig>) -> anyhow::Result<()> {
+    let handshake_timeout = tls
+        .as_ref()
+        .map_or(Duration::ZERO, |config| config.handshake_timeout);
+    let server_config = match tls {
+        Some(config) => {
+            anyhow::ensure!(
+                !config.reload_interval.is_zero(),
+                "the TLS reload interval must not be zero"
+            );
+            let files = TlsFiles::read(&config).await?;
+            let server_config = Arc::new(RwLock::new(files.server_config()?));
+            tokio::spawn(reload(config, files, Arc::downgrade(&server_config)));
+            Some(server_config)
+        }
+        None => None,
+    };
+
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Such as running out of file descriptors, which may not last
+                tracing::error!("Failed to accept connection: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = server_config
+            .as_ref()
+            .map(|server_config| TlsAcceptor::from(server_config.read().unwrap().clone()));
+        let app = app.clone();
+
+        tokio::spawn(async move {
+            let Some(acceptor) = acceptor else {
+                return serve_connection(stream, app, address).await;
+            };
+            match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => serve_connection(stream, app, address).await,
+                Ok(Err(err)) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", peer(address), err)
+                }
+                Err(_) => tracing::debug!("TLS handshake with {} timed out", peer(address)),
+            }
+        });
+    }
+}
+
+async fn serve_connection<I>(io: I, app: Router, address: Option<SocketAddr>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = app.map_request(move |mut request: Request<Incoming>| {
+        if let Some(address) = address {
+            request.extensions_mut().insert(ConnectInfo(address));
+        }
+        request
+    });
+
+    let result = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(service))
+        .await;
+    if let Err(err) = result {
+        tracing::debug!("Connection with {} failed: {}", peer(address), err);
+    }
+}
+
+fn peer(address: Option<SocketAddr>) -> String {
+    match address {
+        Some(address) => address.to_string(),
+        None => "local client".to_string(),
+    }
+}
+
+/// Replaces the server config whenever the files change, until the server is dropped
+async fn reload(
+    config: TlsConfig,
+    mut files: TlsFiles,
+    server_config: Weak<RwLock<Arc<ServerConfig>>>,
+) {
+    let mut interval = tokio::time::interval(config.reload_interval);
+    loop {
+        interval.tick().await;
+        let Some(server_config) = server_config.upgrade() else {
+            return;
+        };
+
+        // The files may be caught half written, in which case they are read again later
+        let reloaded = TlsFiles::read(&config).await.and_then(|reloaded| {
+            if reloaded == files {
+                return Ok(None);
+            }
+            let new_config = reloaded.server_config()?;
+            Ok(Some((reloaded, new_config)))
+        });
+
+        match reloaded {
+            Ok(Some((reloaded, new_config))) => {
+                *server_config.write().unwrap() = new_config;
+                files = reloaded;
+                tracing::info!("Reloaded TLS certificates");
+            }
+            Ok(None) => {}
+            Err(err) => tracing::warn!("Failed to reload TLS certificates: {:#}", err),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+struct TlsFiles {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    client_ca: Option<Vec<u8>>,
+}
+
+impl TlsFiles {
+    async fn read(config: &TlsConfig) -> anyhow::Result<Self> {
+        let client_ca = match &config.client_ca_path {
+            Some(path) => Some(read(path).await?),
+            None => None,
+        };
+        Ok(TlsFiles {
+            cert: read(&config.cert_path).await?,
+            key: read(&config.key_path).await?,
+            client_ca,
+        })
+    }
+
+    fn server_config(&self) -> anyhow::Result<Arc<ServerConfig>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+        let certs = rustls_pemfile::certs(&mut self.cert.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .context("invalid certificate")?;
+        let key = rustls_pemfile::private_key(&mut self.key.as_slice())
+            .context("invalid private key")?
+            .context("no private key found")?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut client_ca.as_slice()) {
+                    roots
+                        .add(cert.context("invalid client CA certificate")?)
+                        .context("invalid client CA certificate")?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+}
+
+async fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))
+}
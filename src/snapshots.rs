@@ -0,0 +1,203 @@
+//! Scheduled snapshots of the database, written to a directory that keeps only the latest
+//! few.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+
+use crate::provider::{BackupProvider, ProviderError};
+
+const PREFIX: &str = "todos-";
+const EXTENSION: &str = ".db";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotConfig {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    /// How many snapshots to keep, the oldest being removed first
+    pub retain: usize,
+}
+
+/// Takes a snapshot every interval, until the task is dropped
+pub async fn schedule(provider: impl BackupProvider, config: SnapshotConfig) {
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        match take(&provider, &config).await {
+            Ok(path) => tracing::info!("Took snapshot {}", path.display()),
+            Err(err) => tracing::error!("Failed to take snapshot: {:#}", err),
+        }
+    }
+}
+
+/// Takes a snapshot, then removes those beyond the retention count, which must be at least
+/// 1 so that the new snapshot is kept
+pub async fn take(
+    provider: &impl BackupProvider,
+    config: &SnapshotConfig,
+) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(config.retain >= 1, "at least one snapshot must be retained");
+    fs::create_dir_all(&config.dir)
+        .with_context(|| format!("failed to create {}", config.dir.display()))?;
+
+    // Named by time in milliseconds, padded so that they sort by name. A snapshot taken
+    // within the same millisecond as the latest is named after it instead.
+    let millis = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_millis();
+    let latest = snapshots(&config.dir)?
+        .first()
+        .and_then(|path| millis_of(path))
+        .map_or(0, |latest| latest + 1);
+    let name = format!("{PREFIX}{:015}{EXTENSION}", millis.max(latest));
+    let path = config.dir.join(&name);
+
+    // Written under another name first, so that an unfinished snapshot is never rotated in
+    let partial = config.dir.join(format!(".{name}.partial"));
+    if let Err(ProviderError(err)) = provider.backup(&partial).await {
+        if let Err(err) = fs::remove_file(&partial) {
+            if err.kind() != io::ErrorKind::NotFound {
+                tracing::warn!("Failed to remove {}: {}", partial.display(), err);
+            }
+        }
+        return Err(err);
+    }
+    fs::rename(&partial, &path)?;
+
+    for removed in rotate(&config.dir, config.retain)? {
+        tracing::debug!("Removed snapshot {}", removed.display());
+    }
+    Ok(path)
+}
+
+/// Removes all but the latest `retain` snapshots in `dir`, returning those removed
+pub fn rotate(dir: &Path, retain: usize) -> io::Result<Vec<PathBuf>> {
+    let mut snapshots = snapshots(dir)?;
+    let removed = snapshots.split_off(retain.min(snapshots.len()));
+    for path in &removed {
+        fs::remove_file(path)?;
+    }
+    Ok(removed)
+}
+
+/// The snapshots in `dir`, latest first
+fn snapshots(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let is_snapshot = name
+            .to_str()
+            .is_some_and(|name| name.starts_with(PREFIX) && name.ends_with(EXTENSION));
+        if is_snapshot && entry.file_type()?.is_file() {
+            snapshots.push(entry.path());
+        }
+    }
+
+    snapshots.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(snapshots)
+}
+
+fn millis_of(path: &Path) -> Option<u128> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix(PREFIX)?
+        .strip_suffix(EXTENSION)?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::MockBackupProvider;
+
+    fn config(dir: &tempfile::TempDir) -> SnapshotConfig {
+        SnapshotConfig {
+            dir: dir.path().to_path_buf(),
+            interval: Duration::from_secs(60),
+            retain: 3,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_take_names_are_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut provider = MockBackupProvider::new();
+        provider.expect_backup().returning(|path| {
+            fs::write(path, "").unwrap();
+            Ok(())
+        });
+
+        // Likely to be taken within the same millisecond
+        let mut taken = vec![];
+        for _ in 0..3 {
+            taken.push(take(&provider, &config(&dir)).await.unwrap());
+        }
+        assert!(taken.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(snapshots(dir.path()).unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_take_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut provider = MockBackupProvider::new();
+        provider.expect_backup().returning(|path| {
+            fs::write(path, "unfinished").unwrap();
+            Err(ProviderError(anyhow::anyhow!("disk full")))
+        });
+
+        assert!(take(&provider, &config(&dir)).await.is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_take_retains_at_least_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let provider = MockBackupProvider::new();
+        let config = SnapshotConfig {
+            retain: 0,
+            ..config(&dir)
+        };
+
+        assert!(take(&provider, &config).await.is_err());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "todos-000000000000001.db",
+            "todos-000000000000003.db",
+            "todos-000000000000002.db",
+            ".todos-000000000000004.db.partial",
+            "notes.txt",
+        ] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        let removed = rotate(dir.path(), 2).unwrap();
+        assert_eq!(removed, vec![dir.path().join("todos-000000000000001.db")]);
+
+        let mut names: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                ".todos-000000000000004.db.partial",
+                "notes.txt",
+                "todos-000000000000002.db",
+                "todos-000000000000003.db",
+            ]
+        );
+
+        assert!(rotate(dir.path(), 5).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+//! Outbound webhooks. Every change to a todo is queued for delivery to each webhook in the
+//! same transaction as the change, so deliveries are neither lost nor made for changes that
+//! were rolled back. Each is sent as a JSON `POST`, which is retried with exponential
+//! backoff until it succeeds or runs out of attempts and becomes a dead letter.
+//!
+//! Deliveries are signed with the webhook's secret: the `X-Webhook-Signature` header holds
+//! `sha256=` followed by the hex HMAC-SHA256 of the request body.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::Semaphore;
+
+use crate::{
+    changes::ChangeKind,
+    endpoints::Todo,
+    provider::{ProviderError, WebhookProvider},
+};
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+/// Identifies a delivery, so that receivers can tell retries of the same delivery apart
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+
+/// A queued delivery along with the webhook it is for
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub url: String,
+    pub secret: String,
+    pub payload: String,
+    /// How many attempts have failed so far
+    pub attempts: i64,
+}
+
+#[derive(Serialize)]
+pub struct Payload<'a> {
+    pub event: ChangeKind,
+    pub todo: &'a Todo,
+}
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    /// How many attempts are made before a delivery becomes a dead letter
+    pub max_attempts: i64,
+    /// How long to wait after the first failed attempt, doubling after each one after that
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// How often to check for deliveries that are due
+    pub poll_interval: Duration,
+    /// How long to wait for a receiver to respond
+    pub timeout: Duration,
+    /// How many deliveries may be waiting on receivers at once
+    pub concurrency: usize,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(60 * 60),
+            poll_interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+            concurrency: 16,
+        }
+    }
+}
+
+impl WebhookConfig {
+    /// How long to wait before retrying a delivery that has failed `attempts` times, or
+    /// `None` if it should not be retried
+    pub fn backoff(&self, attempts: i64) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let exponent = u32::try_from(attempts.saturating_sub(1)).unwrap_or(0);
+        let delay = 2u32
+            .checked_pow(exponent)
+            .and_then(|factor| self.base_delay.checked_mul(factor))
+            .unwrap_or(self.max_delay);
+        Some(delay.min(self.max_delay))
+    }
+}
+
+/// The value of the signature header for `body`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// How many deliveries are fetched at a time
+const BATCH_SIZE: i64 = 100;
+
+/// Makes deliveries as they become due, forever. Each is sent on its own task, so that a
+/// slow receiver only holds up its own deliveries.
+pub async fn deliver<W>(webhooks: W, config: WebhookConfig)
+where
+    W: WebhookProvider + Clone + Send + Sync + 'static,
+{
+    let client = reqwest::Client::builder()
+        .timeout(config.timeout)
+        .build()
+        .expect("the HTTP client is always configured correctly");
+    let config = Arc::new(config);
+    let permits = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    // Deliveries being sent are still pending, so are skipped when fetched again
+    let in_flight = Arc::new(Mutex::new(HashSet::new()));
+
+    let mut interval = tokio::time::interval(config.poll_interval);
+    loop {
+        interval.tick().await;
+        let deliveries = match webhooks.get_due_deliveries(BATCH_SIZE).await {
+            Ok(deliveries) => deliveries,
+            Err(ProviderError(err)) => {
+                tracing::error!("Failed to fetch webhook deliveries: {}", err);
+                continue;
+            }
+        };
+
+        for delivery in deliveries {
+            if !in_flight.lock().unwrap().insert(delivery.id) {
+                continue;
+            }
+            let permit = permits
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+
+            let (webhooks, client, config, in_flight) = (
+                webhooks.clone(),
+                client.clone(),
+                config.clone(),
+                in_flight.clone(),
+            );
+            tokio::spawn(async move {
+                let id = delivery.id;
+                if let Err(ProviderError(err)) =
+                    attempt(&webhooks, &client, &config, delivery).await
+                {
+                    tracing::error!("Failed to record webhook delivery {}: {}", id, err);
+                }
+                in_flight.lock().unwrap().remove(&id);
+                drop(permit);
+            });
+        }
+    }
+}
+
+/// Sends a delivery and records whether it was made
+async fn attempt(
+    webhooks: &impl WebhookProvider,
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+    delivery: WebhookDelivery,
+) -> Result<(), ProviderError> {
+    match send(client, &delivery).await {
+        Ok(()) => webhooks.mark_delivered(delivery.id).await,
+        Err(error) => {
+            let retry_in = config.backoff(delivery.attempts + 1);
+            if retry_in.is_none() {
+                tracing::warn!(
+                    "Giving up on webhook delivery {} to {}: {}",
+                    delivery.id,
+                    delivery.url,
+                    error
+                );
+            }
+            webhooks.mark_failed(delivery.id, &error, retry_in).await
+        }
+    }
+}
+
+async fn send(client: &reqwest::Client, delivery: &WebhookDelivery) -> Result<(), String> {
+    let response = client
+        .post(&delivery.url)
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            mime::APPLICATION_JSON.as_ref(),
+        )
+        .header(
+            SIGNATURE_HEADER,
+            sign(&delivery.secret, delivery.payload.as_bytes()),
+        )
+        .header(DELIVERY_HEADER, delivery.id)
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(format!("receiver responded with {status}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let config = WebhookConfig {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(60),
+            ..WebhookConfig::default()
+        };
+
+        let delays: Vec<_> = (1..=5).map(|attempts| config.backoff(attempts)).collect();
+
+        assert_eq!(
+            delays,
+            [
+                Some(Duration::from_secs(10)),
+                Some(Duration::from_secs(20)),
+                Some(Duration::from_secs(40)),
+                Some(Duration::from_secs(60)),
+                None,
+            ]
+        );
+        assert_eq!(
+            WebhookConfig::default().backoff(1000),
+            None,
+            "no retries past the last attempt"
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        // From RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! A WebSocket protocol for live collaboration. Clients subscribe to a set of todos to be
+//! told about changes to them, and create and update todos over the same socket.
+//!
+//! Every message is a JSON object with a `type`. Clients send `subscribe`, `create` and
+//! `update` messages, each of which may carry a `request_id` that is echoed back in the
+//! `ack` or `error` sent in reply. Changes made by other clients, or through the REST API,
+//! are sent as `change` messages.
+
+use std::collections::HashSet;
+
+use axum::extract::ws::{Message, WebSocket};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    app::AppState,
+    changes::{Change, ChangeKind},
+    endpoints::Todo,
+    provider::{ProviderError, TodoProvider},
+};
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Replaces the set of todos the client is told about. Without `ids`, the client is
+    /// told about every todo, including new ones.
+    Subscribe {
+        request_id: Option<String>,
+        ids: Option<HashSet<i64>>,
+    },
+    Create {
+        request_id: Option<String>,
+        description: String,
+    },
+    Update {
+        request_id: Option<String>,
+        id: i64,
+        description: String,
+        done: bool,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Ack {
+        request_id: Option<String>,
+        /// The todo that was created or updated
+        #[serde(skip_serializing_if = "Option::is_none")]
+        todo: Option<Todo>,
+    },
+    Error {
+        request_id: Option<String>,
+        message: String,
+    },
+    Change {
+        id: u64,
+        kind: ChangeKind,
+        todo: Todo,
+    },
+}
+
+/// Which todos a client is told about
+enum Subscription {
+    None,
+    All,
+    Ids(HashSet<i64>),
+}
+
+impl Subscription {
+    fn includes(&self, change: &Change) -> bool {
+        match self {
+            Subscription::None => false,
+            Subscription::All => true,
+            Subscription::Ids(ids) => ids.contains(&change.todo.id),
+        }
+    }
+}
+
+/// Serves a single client until it disconnects. Changes are recorded as made by `actor`.
+pub async fn serve<A: AppState>(mut socket: WebSocket, state: A, actor: String) {
+    let (_, mut changes) = state.changes().subscribe(None);
+    let mut subscription = Subscription::None;
+    // Changes made by this client, which it has already been told about in an ack
+    let mut own_changes = HashSet::new();
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    // Pings are answered automatically
+                    Some(Ok(_)) => continue,
+                };
+
+                match serde_json::from_str(&text) {
+                    Ok(message) => {
+                        handle(&state, &actor, message, &mut subscription, &mut own_changes).await
+                    }
+                    Err(err) => ServerMessage::Error {
+                        request_id: None,
+                        message: format!("invalid message: {err}"),
+                    },
+                }
+            }
+            change = changes.recv() => match change {
+                Ok(change) if own_changes.remove(&change.id) => continue,
+                Ok(change) if subscription.includes(&change) => ServerMessage::Change {
+                    id: change.id,
+                    kind: change.kind,
+                    todo: change.todo,
+                },
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    // Any of this client's own changes may have been among those missed
+                    own_changes.clear();
+                    ServerMessage::Error {
+                        request_id: None,
+                        message: format!("missed {missed} changes"),
+                    }
+                }
+                Err(RecvError::Closed) => return,
+            },
+        };
+
+        let reply = serde_json::to_string(&reply).expect("messages always serialize");
+        if socket.send(Message::Text(reply)).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn handle<A: AppState>(
+    state: &A,
+    actor: &str,
+    message: ClientMessage,
+    subscription: &mut Subscription,
+    own_changes: &mut HashSet<u64>,
+) -> ServerMessage {
+    let (request_id, result) = match message {
+        ClientMessage::Subscribe { request_id, ids } => {
+            *subscription = match ids {
+                Some(ids) => Subscription::Ids(ids),
+                None => Subscription::All,
+            };
+            (request_id, Ok(None))
+        }
+        ClientMessage::Create {
+            request_id,
+            description,
+        } => {
+            let result = state.provider().add_todo(&description, actor).await;
+            (request_id, publish(state, ChangeKind::Created, result))
+        }
+        ClientMessage::Update {
+            request_id,
+            id,
+            description,
+            done,
+        } => {
+            let result = match state
+                .provider()
+                .update_todo(id, &description, done, actor)
+                .await
+            {
+                Ok(Some(todo)) => publish(state, ChangeKind::Updated, Ok(todo)),
+                Ok(None) => Err(format!("todo {id} not found")),
+                Err(err) => Err(internal_error(err)),
+            };
+            (request_id, result)
+        }
+    };
+
+    match result {
+        Ok(Some((change_id, todo))) => {
+            own_changes.insert(change_id);
+            ServerMessage::Ack {
+                request_id,
+                todo: Some(todo),
+            }
+        }
+        Ok(None) => ServerMessage::Ack {
+            request_id,
+            todo: None,
+        },
+        Err(message) => ServerMessage::Error {
+            request_id,
+            message,
+        },
+    }
+}
+
+/// Publishes the result of a change, returning the id of the change along with the todo
+fn publish<A: AppState>(
+    state: &A,
+    kind: ChangeKind,
+    result: Result<Todo, ProviderError>,
+) -> Result<Option<(u64, Todo)>, String> {
+    let todo = result.map_err(internal_error)?;
+    let change = state.changes().publish(kind, todo.clone());
+    Ok(Some((change.id, todo)))
+}
+
+fn internal_error(ProviderError(err): ProviderError) -> String {
+    tracing::error!("{}", err);
+    "internal error".to_string()
+}
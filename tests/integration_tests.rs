@@ -1,10 +1,44 @@
-use std::net::SocketAddr;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use axum::{
-    body::Body,
-    http::{HeaderName, HeaderValue},
+    body::{Body, Bytes},
+    extract::State,
+    http::{HeaderMap, HeaderName, HeaderValue},
+    routing::post,
+    Router,
+};
+use axum_sqlx_mockall_todos::{
+    admin::{self, MigrationState},
+    app,
+    cache::{CacheConfig, CacheLayer, CacheStats},
+    changes::ChangeFeed,
+    client::TodoClient,
+    config::AppConfig,
+    db::{
+        DatabaseConfig, SqliteBackupProvider, SqlitePools, SqliteTodoProvider,
+        SqliteWebhookProvider,
+    },
+    endpoints::{self, BatchMode, BatchOperation},
+    grpc::{
+        self,
+        proto::{todos_client::TodosClient, GetTodoRequest, GetTodosRequest, TodoAdd, TodoUpdate},
+    },
+    layer::ProviderBuilder,
+    listener::{Listener, ListenerConfig},
+    provider::{ProviderError, TodoProvider, WebhookProvider},
+    rate_limit::{Budget, RateLimitConfig, RateLimiter},
+    resilience::{ResilienceConfig, ResilienceLayer},
+    server::{self, TlsConfig},
+    snapshots::{self, SnapshotConfig},
+    webhooks::{self, WebhookConfig},
+    SqliteAppState,
 };
-use axum_sqlx_mockall_todos::{app, db::SqliteTodoProvider, SqliteAppState};
+use futures_util::{SinkExt, StreamExt};
 use http_body_util::BodyExt;
 use hyper::{
     client::conn::http1::{handshake, SendRequest},
@@ -12,18 +46,45 @@ use hyper::{
 };
 use hyper_util::rt::TokioIo;
 use serde_json::{json, Value};
-use sqlx::{Pool, Sqlite};
-use tokio::net::{TcpListener, TcpStream};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteConnection, SqliteJournalMode},
+    Connection, Pool, Sqlite, SqlitePool,
+};
+use tokio::{
+    net::{TcpListener, TcpStream, UnixStream},
+    sync::mpsc,
+};
+use tokio_tungstenite::{
+    tungstenite::{client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use tonic::transport::{Channel, Endpoint, Uri};
+
+fn app_state(pool: &Pool<Sqlite>) -> SqliteAppState {
+    SqliteAppState {
+        provider: SqliteTodoProvider::from(pool),
+        webhooks: SqliteWebhookProvider::from(pool),
+        backups: SqliteBackupProvider::from(pool),
+        changes: ChangeFeed::default(),
+        rate_limiter: RateLimiter::default(),
+        config: AppConfig::default(),
+    }
+}
 
 async fn spawn_server(pool: Pool<Sqlite>) -> SocketAddr {
-    let provider = SqliteTodoProvider::from(&pool);
-    let state = SqliteAppState { provider };
+    serve(app_state(&pool)).await
+}
 
+async fn serve<P>(state: SqliteAppState<P>) -> SocketAddr
+where
+    P: TodoProvider + Clone + Send + Sync + 'static,
+{
     let listener = TcpListener::bind("0.0.0.0:0").await.unwrap();
     let address = listener.local_addr().unwrap();
 
     tokio::spawn(async move {
-        axum::serve(listener, app::router(state)).await.unwrap();
+        let app = app::router(state).into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, app).await.unwrap();
     });
 
     address
@@ -119,12 +180,22 @@ async fn test_not_found(pool: Pool<Sqlite>) {
 
     let req = Request::builder()
         .uri(format!("http://{address}/todos/100"))
+        .header("X-Request-Id", "abc-123")
         .body(Body::empty())
         .unwrap();
 
     let res = client.send_request(req).await.unwrap();
 
     assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(res.headers()["x-request-id"], "abc-123");
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(
+        body,
+        json!({ "error": "not found", "request_id": "abc-123" })
+    );
 }
 
 #[sqlx::test]
@@ -215,3 +286,1832 @@ async fn test_update_todo(pool: Pool<Sqlite>) {
     assert_eq!(body["description"], "test 1");
     assert_eq!(body["done"], true);
 }
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_delete_and_restore_todo(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .method(http::Method::DELETE)
+        .uri(format!("http://{address}/todos/1"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos/1"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/trash"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().iter().any(has_json_content_type));
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["id"], 1);
+    assert_eq!(body[0]["description"], "test 1");
+    assert!(body[0]["deleted_at"].is_i64());
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/trash/1/restore"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos/1"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body["id"], 1);
+    assert_eq!(body["description"], "test 1");
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_purge_trash(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    for id in [1, 2] {
+        let req = Request::builder()
+            .method(http::Method::DELETE)
+            .uri(format!("http://{address}/todos/{id}"))
+            .body(Body::empty())
+            .unwrap();
+
+        let res = client.send_request(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+
+    let req = Request::builder()
+        .method(http::Method::DELETE)
+        .uri(format!("http://{address}/trash"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body, json!({ "purged": 2 }));
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/trash/1/restore"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(
+        body,
+        json!([{
+            "id": 3,
+            "description": "test 3",
+            "done": false
+        }])
+    );
+}
+
+#[sqlx::test]
+async fn test_get_history(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .header("X-Actor", "alice")
+        .body(Body::from(
+            serde_json::to_vec(&json!({
+                "description": "test 1"
+            }))
+            .unwrap(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let req = Request::builder()
+        .method(http::Method::PUT)
+        .uri(format!("http://{address}/todos/1"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .header("X-Actor", "bob")
+        .body(Body::from(
+            serde_json::to_vec(&json!({
+                "description": "test 2",
+                "done": true
+            }))
+            .unwrap(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = Request::builder()
+        .method(http::Method::DELETE)
+        .uri(format!("http://{address}/todos/1"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos/1/history"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().iter().any(has_json_content_type));
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let mut body: Value = serde_json::from_slice(&body).unwrap();
+
+    for revision in body.as_array_mut().unwrap() {
+        assert!(revision["created_at"].is_i64());
+        revision.as_object_mut().unwrap().remove("created_at");
+    }
+
+    assert_eq!(
+        body,
+        json!([{
+            "revision": 1,
+            "action": "created",
+            "old_description": null,
+            "old_done": null,
+            "new_description": "test 1",
+            "new_done": false,
+            "actor": "alice"
+        }, {
+            "revision": 2,
+            "action": "updated",
+            "old_description": "test 1",
+            "old_done": false,
+            "new_description": "test 2",
+            "new_done": true,
+            "actor": "bob"
+        }, {
+            "revision": 3,
+            "action": "deleted",
+            "old_description": "test 2",
+            "old_done": true,
+            "new_description": null,
+            "new_done": null,
+            "actor": "anonymous"
+        }])
+    );
+}
+
+#[sqlx::test]
+async fn test_restore_revision(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(Body::from(
+            serde_json::to_vec(&json!({
+                "description": "test 1"
+            }))
+            .unwrap(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let req = Request::builder()
+        .method(http::Method::PUT)
+        .uri(format!("http://{address}/todos/1"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(Body::from(
+            serde_json::to_vec(&json!({
+                "description": "oops",
+                "done": true
+            }))
+            .unwrap(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos/1/revisions/1/restore"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(
+        body,
+        json!({
+            "id": 1,
+            "description": "test 1",
+            "done": false
+        })
+    );
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos/1/history"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body.as_array().unwrap().len(), 3);
+    assert_eq!(body[2]["action"], "updated");
+    assert_eq!(body[2]["old_description"], "oops");
+    assert_eq!(body[2]["new_description"], "test 1");
+    assert_eq!(body[2]["new_done"], false);
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_apply_batch(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos/batch"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(Body::from(
+            serde_json::to_vec(&json!({
+                "operations": [
+                    { "op": "create", "description": "test 4" },
+                    { "op": "update", "id": 1, "description": "test 1", "done": true },
+                    { "op": "delete", "id": 2 }
+                ]
+            }))
+            .unwrap(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().iter().any(has_json_content_type));
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(
+        body,
+        json!({
+            "committed": true,
+            "results": [{
+                "status": "ok",
+                "todo": { "id": 4, "description": "test 4", "done": false }
+            }, {
+                "status": "ok",
+                "todo": { "id": 1, "description": "test 1", "done": true }
+            }, {
+                "status": "ok",
+                "todo": { "id": 2, "description": "test 2", "done": false }
+            }]
+        })
+    );
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(
+        body,
+        json!([{
+            "id": 1,
+            "description": "test 1",
+            "done": true
+        }, {
+            "id": 3,
+            "description": "test 3",
+            "done": false
+        }, {
+            "id": 4,
+            "description": "test 4",
+            "done": false
+        }])
+    );
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_apply_batch_atomic_failure(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos/batch"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(Body::from(
+            serde_json::to_vec(&json!({
+                "mode": "atomic",
+                "operations": [
+                    { "op": "delete", "id": 1 },
+                    { "op": "delete", "id": 100 },
+                    { "op": "delete", "id": 2 }
+                ]
+            }))
+            .unwrap(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(
+        body,
+        json!({
+            "committed": false,
+            "results": [
+                { "status": "rolled_back" },
+                { "status": "not_found" },
+                { "status": "skipped" }
+            ]
+        })
+    );
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body.as_array().unwrap().len(), 3);
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_apply_batch_best_effort(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos/batch"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(Body::from(
+            serde_json::to_vec(&json!({
+                "mode": "best_effort",
+                "operations": [
+                    { "op": "delete", "id": 1 },
+                    { "op": "update", "id": 100, "description": "test 100", "done": true },
+                    { "op": "delete", "id": 2 }
+                ]
+            }))
+            .unwrap(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body["committed"], true);
+    assert_eq!(body["results"][0]["status"], "ok");
+    assert_eq!(body["results"][1]["status"], "not_found");
+    assert_eq!(body["results"][2]["status"], "ok");
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(
+        body,
+        json!([{
+            "id": 3,
+            "description": "test 3",
+            "done": false
+        }])
+    );
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_export_csv(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos/export.csv"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        mime::TEXT_CSV_UTF_8.as_ref()
+    );
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+
+    assert_eq!(
+        body,
+        "id,description,done\n1,test 1,false\n2,test 2,false\n3,test 3,false\n"
+    );
+}
+
+#[sqlx::test]
+async fn test_import_csv(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos/import"))
+        .header(header::CONTENT_TYPE, mime::TEXT_CSV.as_ref())
+        .body(Body::from("description,done\ntest 1,false\ntest 2,true\n"))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(
+        body,
+        json!([{
+            "id": 1,
+            "description": "test 1",
+            "done": false
+        }, {
+            "id": 2,
+            "description": "test 2",
+            "done": true
+        }])
+    );
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_ical_round_trip(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos.ics"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        "text/calendar; charset=utf-8"
+    );
+
+    let calendar = res.into_body().collect().await.unwrap().to_bytes();
+
+    assert_eq!(
+        std::str::from_utf8(&calendar)
+            .unwrap()
+            .matches("BEGIN:VTODO")
+            .count(),
+        3
+    );
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos/import"))
+        .header(header::CONTENT_TYPE, "text/calendar")
+        .body(Body::from(calendar))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(body.as_array().unwrap().len(), 6);
+    assert_eq!(body[3]["description"], "test 1");
+    assert_eq!(body[5]["description"], "test 3");
+}
+
+#[sqlx::test]
+async fn test_todo_txt_round_trip(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut client = client(address).await;
+
+    let todo_txt = "(A) Call Mom +family @phone\nx Buy milk @store\n";
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos/import"))
+        .header(header::CONTENT_TYPE, mime::TEXT_PLAIN.as_ref())
+        .body(Body::from(todo_txt))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos"))
+        .header(header::ACCEPT, "text/plain")
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        mime::TEXT_PLAIN_UTF_8.as_ref()
+    );
+
+    let body = res.into_body().collect().await.unwrap().to_bytes();
+
+    assert_eq!(body, todo_txt);
+}
+
+#[sqlx::test]
+async fn test_todo_events(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut events_client = client(address).await;
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos/events"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = events_client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers()[header::CONTENT_TYPE],
+        mime::TEXT_EVENT_STREAM.as_ref()
+    );
+
+    let mut events = res.into_body();
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(Body::from(
+            serde_json::to_vec(&json!({
+                "description": "test 1"
+            }))
+            .unwrap(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let frame = events.frame().await.unwrap().unwrap();
+    let event = std::str::from_utf8(frame.data_ref().unwrap()).unwrap();
+
+    assert_eq!(
+        event,
+        "id: 1\nevent: created\ndata: {\"id\":1,\"description\":\"test 1\",\"done\":false}\n\n"
+    );
+}
+
+async fn websocket(address: SocketAddr) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+    let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{address}/ws"))
+        .await
+        .unwrap();
+    socket
+}
+
+async fn send_json(socket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>, message: Value) {
+    socket
+        .send(Message::Text(message.to_string()))
+        .await
+        .unwrap();
+}
+
+async fn recv_json(socket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> Value {
+    loop {
+        match socket.next().await.unwrap().unwrap() {
+            Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+            _ => continue,
+        }
+    }
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_websocket(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let mut alice = websocket(address).await;
+    let mut bob = websocket(address).await;
+
+    for socket in [&mut alice, &mut bob] {
+        send_json(socket, json!({ "type": "subscribe", "request_id": "s" })).await;
+        assert_eq!(
+            recv_json(socket).await,
+            json!({ "type": "ack", "request_id": "s" })
+        );
+    }
+
+    send_json(
+        &mut alice,
+        json!({ "type": "create", "request_id": "c", "description": "test 4" }),
+    )
+    .await;
+
+    assert_eq!(
+        recv_json(&mut alice).await,
+        json!({
+            "type": "ack",
+            "request_id": "c",
+            "todo": { "id": 4, "description": "test 4", "done": false }
+        })
+    );
+    assert_eq!(
+        recv_json(&mut bob).await,
+        json!({
+            "type": "change",
+            "id": 1,
+            "kind": "created",
+            "todo": { "id": 4, "description": "test 4", "done": false }
+        })
+    );
+
+    send_json(
+        &mut bob,
+        json!({ "type": "subscribe", "request_id": "s", "ids": [1] }),
+    )
+    .await;
+    recv_json(&mut bob).await;
+
+    send_json(
+        &mut alice,
+        json!({ "type": "update", "id": 2, "description": "test 2", "done": true }),
+    )
+    .await;
+    recv_json(&mut alice).await;
+    send_json(
+        &mut alice,
+        json!({ "type": "update", "request_id": "u", "id": 1, "description": "test 1", "done": true }),
+    )
+    .await;
+    recv_json(&mut alice).await;
+
+    // Bob is only subscribed to the first todo, so is not told about the change to the second
+    assert_eq!(
+        recv_json(&mut bob).await,
+        json!({
+            "type": "change",
+            "id": 3,
+            "kind": "updated",
+            "todo": { "id": 1, "description": "test 1", "done": true }
+        })
+    );
+
+    send_json(
+        &mut alice,
+        json!({ "type": "update", "request_id": "u", "id": 100, "description": "test", "done": true }),
+    )
+    .await;
+
+    assert_eq!(
+        recv_json(&mut alice).await,
+        json!({ "type": "error", "request_id": "u", "message": "todo 100 not found" })
+    );
+
+    send_json(&mut alice, json!({ "type": "delete", "id": 1 })).await;
+
+    assert_eq!(recv_json(&mut alice).await["type"], "error");
+}
+
+const ADMIN_TOKEN: &str = "admin";
+
+/// Serves the app along with the webhook workers, retrying failed deliveries straight away
+async fn spawn_server_with_webhooks(pool: Pool<Sqlite>, max_attempts: i64) -> SocketAddr {
+    let mut state = app_state(&pool);
+    state.config.admin_token = Some(ADMIN_TOKEN.to_string());
+    let config = WebhookConfig {
+        max_attempts,
+        base_delay: Duration::ZERO,
+        poll_interval: Duration::from_millis(20),
+        ..WebhookConfig::default()
+    };
+
+    tokio::spawn(webhooks::deliver(state.webhooks.clone(), config));
+
+    serve(state).await
+}
+
+struct Receiver {
+    url: String,
+    deliveries: mpsc::UnboundedReceiver<(HeaderMap, Bytes)>,
+}
+
+impl Receiver {
+    async fn next(&mut self) -> (HeaderMap, Bytes) {
+        tokio::time::timeout(Duration::from_secs(10), self.deliveries.recv())
+            .await
+            .expect("no delivery received")
+            .unwrap()
+    }
+}
+
+/// Spawns a webhook receiver that responds with each of `statuses` in turn, then with 200
+async fn spawn_receiver(statuses: Vec<StatusCode>) -> Receiver {
+    type ReceiverState = (
+        Arc<Mutex<VecDeque<StatusCode>>>,
+        mpsc::UnboundedSender<(HeaderMap, Bytes)>,
+    );
+
+    async fn receive(
+        State((statuses, sender)): State<ReceiverState>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        sender.send((headers, body)).unwrap();
+        statuses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(StatusCode::OK)
+    }
+
+    let (sender, deliveries) = mpsc::unbounded_channel();
+    let app = Router::new()
+        .route("/hook", post(receive))
+        .with_state((Arc::new(Mutex::new(statuses.into())), sender));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    Receiver {
+        url: format!("http://{address}/hook"),
+        deliveries,
+    }
+}
+
+async fn add_webhook(address: SocketAddr, url: &str) {
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/webhooks"))
+        .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(Body::from(
+            json!({ "url": url, "secret": "s3cret" }).to_string(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+}
+
+async fn add_todo(address: SocketAddr, description: &str) {
+    let mut client = client(address).await;
+
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/todos"))
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+        .body(Body::from(
+            json!({ "description": description }).to_string(),
+        ))
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::CREATED);
+}
+
+#[sqlx::test]
+async fn test_webhook_delivery(pool: Pool<Sqlite>) {
+    let address = spawn_server_with_webhooks(pool, 3).await;
+    let mut receiver = spawn_receiver(vec![StatusCode::INTERNAL_SERVER_ERROR]).await;
+
+    add_webhook(address, &receiver.url).await;
+    add_todo(address, "test 1").await;
+
+    let (first_headers, first_body) = receiver.next().await;
+    let (headers, body) = receiver.next().await;
+
+    // The failed delivery is retried with the same payload
+    assert_eq!(body, first_body);
+    assert_eq!(
+        headers[webhooks::DELIVERY_HEADER],
+        first_headers[webhooks::DELIVERY_HEADER]
+    );
+    assert_eq!(
+        headers[webhooks::SIGNATURE_HEADER],
+        webhooks::sign("s3cret", &body).as_str()
+    );
+    assert_eq!(
+        headers[header::CONTENT_TYPE],
+        mime::APPLICATION_JSON.as_ref()
+    );
+
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        body,
+        json!({
+            "event": "created",
+            "todo": { "id": 1, "description": "test 1", "done": false }
+        })
+    );
+}
+
+#[sqlx::test]
+async fn test_webhook_dead_letter(pool: Pool<Sqlite>) {
+    let address = spawn_server_with_webhooks(pool, 2).await;
+    let mut receiver = spawn_receiver(vec![StatusCode::INTERNAL_SERVER_ERROR; 2]).await;
+
+    add_webhook(address, &receiver.url).await;
+    add_todo(address, "test 1").await;
+
+    receiver.next().await;
+    receiver.next().await;
+
+    let mut client = client(address).await;
+
+    // The dead letter is recorded after the last attempt has been responded to
+    let dead_letters = loop {
+        let req = Request::builder()
+            .uri(format!("http://{address}/webhooks/dead-letters"))
+            .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+            .body(Body::empty())
+            .unwrap();
+
+        let res = client.send_request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        if body != json!([]) {
+            break body;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+
+    assert_eq!(dead_letters[0]["attempts"], 2);
+    assert_eq!(
+        dead_letters[0]["last_error"],
+        "receiver responded with 500 Internal Server Error"
+    );
+    assert_eq!(dead_letters[0]["url"], receiver.url);
+
+    let id = &dead_letters[0]["id"];
+    let req = Request::builder()
+        .method(http::Method::POST)
+        .uri(format!("http://{address}/webhooks/dead-letters/{id}/retry"))
+        .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+        .body(Body::empty())
+        .unwrap();
+
+    let res = client.send_request(req).await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+
+    let (_, body) = receiver.next().await;
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["event"], "created");
+}
+
+#[sqlx::test]
+async fn test_webhook_deliveries_queued_with_writes(pool: Pool<Sqlite>) {
+    let webhooks = SqliteWebhookProvider::from(&pool);
+    webhooks
+        .add_webhook("http://example.com/hook", "s3cret")
+        .await
+        .unwrap_or_else(|ProviderError(err)| panic!("{err}"));
+    let provider = SqliteTodoProvider::from(&pool);
+
+    let queued = || async {
+        let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM webhook_deliveries")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        count
+    };
+
+    // Queued by the write itself, with nothing listening for changes
+    let todo = provider
+        .add_todo("test 1", "test")
+        .await
+        .unwrap_or_else(|ProviderError(err)| panic!("{err}"));
+    assert_eq!(queued().await, 1);
+
+    // Deliveries for a rolled back batch are rolled back with it
+    let outcome = provider
+        .apply_batch(
+            vec![
+                BatchOperation::Update {
+                    id: todo.id,
+                    description: "test 1".to_string(),
+                    done: true,
+                },
+                BatchOperation::Update {
+                    id: 1_000_000,
+                    description: "missing".to_string(),
+                    done: true,
+                },
+            ],
+            BatchMode::Atomic,
+            "test",
+        )
+        .await
+        .unwrap_or_else(|ProviderError(err)| panic!("{err}"));
+    assert!(!outcome.committed);
+    assert_eq!(queued().await, 1);
+
+    provider
+        .delete_todo(todo.id, "test")
+        .await
+        .unwrap_or_else(|ProviderError(err)| panic!("{err}"));
+    assert_eq!(queued().await, 2);
+}
+
+#[sqlx::test]
+async fn test_webhook_slow_receiver(pool: Pool<Sqlite>) {
+    let address = spawn_server_with_webhooks(pool, 3).await;
+
+    // Accepts connections but never responds, so its deliveries wait for the timeout
+    let hanging = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hanging_url = format!("http://{}/hook", hanging.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut connections = vec![];
+        loop {
+            connections.push(hanging.accept().await.unwrap());
+        }
+    });
+    let mut receiver = spawn_receiver(vec![]).await;
+
+    add_webhook(address, &hanging_url).await;
+    add_webhook(address, &receiver.url).await;
+    add_todo(address, "test 1").await;
+
+    let (_, body) = tokio::time::timeout(Duration::from_secs(2), receiver.next())
+        .await
+        .expect("delivery held up by the slow receiver");
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["event"], "created");
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_graphql_subscription(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+
+    let mut request = format!("ws://{address}/graphql/ws")
+        .into_client_request()
+        .unwrap();
+    request.headers_mut().insert(
+        header::SEC_WEBSOCKET_PROTOCOL,
+        HeaderValue::from_static("graphql-transport-ws"),
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+    send_json(&mut socket, json!({ "type": "connection_init" })).await;
+    assert_eq!(
+        recv_json(&mut socket).await,
+        json!({ "type": "connection_ack" })
+    );
+
+    send_json(
+        &mut socket,
+        json!({
+            "id": "1",
+            "type": "subscribe",
+            "payload": { "query": "subscription { changes(ids: [2]) { kind todo { id done } } }" }
+        }),
+    )
+    .await;
+    // There is no acknowledgement of a subscription, so give the server a moment to start it
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut client = client(address).await;
+    for id in [1, 2] {
+        let req = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("http://{address}/graphql"))
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                json!({
+                    "query": format!(
+                        "mutation {{ updateTodo(id: {id}, description: \"test {id}\", done: true) {{ id }} }}"
+                    )
+                })
+                .to_string(),
+            ))
+            .unwrap();
+
+        let res = client.send_request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = res.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({ "data": { "updateTodo": { "id": id } } }));
+    }
+
+    // Only the change to the second todo is sent
+    assert_eq!(
+        recv_json(&mut socket).await,
+        json!({
+            "id": "1",
+            "type": "next",
+            "payload": {
+                "data": { "changes": { "kind": "UPDATED", "todo": { "id": 2, "done": true } } }
+            }
+        })
+    );
+}
+
+/// A gRPC client for a service running in-process, connected without any network
+async fn grpc_client(state: SqliteAppState) -> TodosClient<Channel> {
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+
+    tokio::spawn(
+        tonic::transport::Server::builder()
+            .add_service(grpc::service(state))
+            .serve_with_incoming(futures_util::stream::iter([Ok::<_, std::io::Error>(
+                server_io,
+            )])),
+    );
+
+    let mut client_io = Some(client_io);
+    let channel = Endpoint::from_static("http://in-process")
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let client_io = client_io.take();
+            async move {
+                client_io
+                    .map(TokioIo::new)
+                    .ok_or_else(|| std::io::Error::other("already connected"))
+            }
+        }))
+        .await
+        .unwrap();
+
+    TodosClient::new(channel)
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_grpc(pool: Pool<Sqlite>) {
+    let state = app_state(&pool);
+    let mut client = grpc_client(state.clone()).await;
+
+    let todos = client
+        .get_todos(GetTodosRequest {})
+        .await
+        .unwrap()
+        .into_inner()
+        .todos;
+    assert_eq!(todos.len(), 3);
+    assert_eq!(todos[0].description, "test 1");
+
+    let mut request = tonic::Request::new(TodoAdd {
+        description: "test 4".to_string(),
+    });
+    request
+        .metadata_mut()
+        .insert("x-actor", "alice".parse().unwrap());
+    let todo = client.add_todo(request).await.unwrap().into_inner();
+    assert_eq!(todo.id, 4);
+    assert_eq!(todo.description, "test 4");
+    assert!(!todo.done);
+
+    let todo = client
+        .update_todo(TodoUpdate {
+            id: 4,
+            description: "test 4".to_string(),
+            done: true,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(todo.done);
+
+    let todo = client
+        .get_todo(GetTodoRequest { id: 4 })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(todo.done);
+
+    let history = state
+        .provider
+        .get_history(4)
+        .await
+        .unwrap_or_else(|ProviderError(err)| panic!("{err}"));
+    let actors: Vec<_> = history
+        .iter()
+        .map(|revision| revision.actor.as_str())
+        .collect();
+    assert_eq!(actors, ["alice", "anonymous"]);
+
+    let status = client
+        .update_todo(TodoUpdate {
+            id: 100,
+            description: "test".to_string(),
+            done: true,
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+
+    let status = client
+        .get_todo(GetTodoRequest { id: 100 })
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_grpc_same_port(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+
+    // gRPC and REST share the port
+    let mut grpc_client = TodosClient::connect(format!("http://{address}"))
+        .await
+        .unwrap();
+    let todo = grpc_client
+        .get_todo(GetTodoRequest { id: 2 })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(todo.description, "test 2");
+
+    let mut client = client(address).await;
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos/2"))
+        .body(Body::empty())
+        .unwrap();
+    let res = client.send_request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_grpc_router(pool: Pool<Sqlite>) {
+    let state = SqliteAppState {
+        rate_limiter: RateLimiter::new(RateLimitConfig {
+            write: Budget::per_minute(1),
+            ..RateLimitConfig::default()
+        }),
+        ..app_state(&pool)
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(server::serve(
+        Listener::Tcp(listener),
+        app::grpc_router(state),
+        None,
+    ));
+
+    let mut client = TodosClient::connect(format!("http://{address}"))
+        .await
+        .unwrap();
+    let todos = client.get_todos(GetTodosRequest {}).await.unwrap();
+    assert_eq!(todos.into_inner().todos.len(), 3);
+
+    // Calls are rate limited as they are on the main port
+    let status = client.get_todos(GetTodosRequest {}).await.unwrap_err();
+    assert_eq!(status.metadata().get("retry-after").unwrap(), "60");
+}
+
+#[sqlx::test]
+async fn test_rate_limit(pool: Pool<Sqlite>) {
+    let address = serve(SqliteAppState {
+        rate_limiter: RateLimiter::new(RateLimitConfig {
+            read: Budget::per_minute(10),
+            write: Budget::per_minute(1),
+            api_keys: ["abc".to_string()].into(),
+        }),
+        ..app_state(&pool)
+    })
+    .await;
+    let mut client = client(address).await;
+
+    let add_todo = |api_key: Option<&str>| {
+        let mut req = Request::builder()
+            .method(http::Method::POST)
+            .uri(format!("http://{address}/todos"))
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref());
+        if let Some(api_key) = api_key {
+            req = req.header("X-Api-Key", api_key);
+        }
+        req.body(Body::from(json!({ "description": "test" }).to_string()))
+            .unwrap()
+    };
+
+    let res = client.send_request(add_todo(None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.headers()["ratelimit-limit"], "1");
+    assert_eq!(res.headers()["ratelimit-remaining"], "0");
+    assert_eq!(res.headers()["ratelimit-reset"], "60");
+
+    let res = client.send_request(add_todo(None)).await.unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers()[header::RETRY_AFTER], "60");
+
+    // Reads have their own budget
+    let req = Request::builder()
+        .uri(format!("http://{address}/todos"))
+        .body(Body::empty())
+        .unwrap();
+    let res = client.send_request(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["ratelimit-limit"], "10");
+    assert_eq!(res.headers()["ratelimit-remaining"], "9");
+
+    // Unknown keys are limited by address, so cannot be made up for a fresh budget
+    let res = client
+        .send_request(add_todo(Some("made-up")))
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Clients with a known API key are limited by key rather than address
+    let res = client.send_request(add_todo(Some("abc"))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let res = client.send_request(add_todo(Some("abc"))).await.unwrap();
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+struct CertificateAuthority {
+    cert: rcgen::Certificate,
+    key: rcgen::KeyPair,
+}
+
+impl CertificateAuthority {
+    fn new() -> Self {
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "Test CA");
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        CertificateAuthority { cert, key }
+    }
+
+    /// Issues a certificate for `localhost`, returning it and its key as PEM
+    fn issue(&self, usage: rcgen::ExtendedKeyUsagePurpose) -> (String, String) {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.extended_key_usages = vec![usage];
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    fn pem(&self) -> String {
+        self.cert.pem()
+    }
+}
+
+/// Serves the app over HTTPS with a certificate for `localhost` issued by `ca`
+async fn spawn_tls_server(
+    pool: Pool<Sqlite>,
+    dir: &tempfile::TempDir,
+    ca: &CertificateAuthority,
+    client_ca: Option<&CertificateAuthority>,
+) -> (SocketAddr, TlsConfig) {
+    let (cert, key) = ca.issue(rcgen::ExtendedKeyUsagePurpose::ServerAuth);
+    let config = TlsConfig {
+        cert_path: dir.path().join("cert.pem"),
+        key_path: dir.path().join("key.pem"),
+        client_ca_path: client_ca.map(|_| dir.path().join("client_ca.pem")),
+        reload_interval: Duration::from_millis(50),
+        handshake_timeout: Duration::from_millis(200),
+    };
+    std::fs::write(&config.cert_path, cert).unwrap();
+    std::fs::write(&config.key_path, key).unwrap();
+    if let (Some(path), Some(client_ca)) = (&config.client_ca_path, client_ca) {
+        std::fs::write(path, client_ca.pem()).unwrap();
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let app = app::router(app_state(&pool));
+
+    tokio::spawn(server::serve(
+        Listener::Tcp(listener),
+        app,
+        Some(config.clone()),
+    ));
+
+    (address, config)
+}
+
+fn https_client(
+    address: SocketAddr,
+    ca: &CertificateAuthority,
+    identity: Option<reqwest::Identity>,
+) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(reqwest::Certificate::from_pem(ca.pem().as_bytes()).unwrap())
+        .resolve("localhost", address);
+    if let Some(identity) = identity {
+        builder = builder.identity(identity);
+    }
+    builder.build().unwrap()
+}
+
+async fn https_get_todos(client: &reqwest::Client, address: SocketAddr) -> reqwest::Result<Value> {
+    let body = client
+        .get(format!("https://localhost:{}/todos", address.port()))
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(serde_json::from_slice(&body).unwrap())
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_tls(pool: Pool<Sqlite>) {
+    let dir = tempfile::tempdir().unwrap();
+    let ca = CertificateAuthority::new();
+    let (address, config) = spawn_tls_server(pool, &dir, &ca, None).await;
+
+    let client = https_client(address, &ca, None);
+    let todos = https_get_todos(&client, address).await.unwrap();
+    assert_eq!(todos.as_array().unwrap().len(), 3);
+
+    // Clients not trusting the CA are turned away
+    let other_ca = CertificateAuthority::new();
+    let other_client = https_client(address, &other_ca, None);
+    assert!(https_get_todos(&other_client, address).await.is_err());
+
+    // Replace the certificate with one from the other CA
+    let (cert, key) = other_ca.issue(rcgen::ExtendedKeyUsagePurpose::ServerAuth);
+    std::fs::write(&config.key_path, key).unwrap();
+    std::fs::write(&config.cert_path, cert).unwrap();
+
+    let reloaded = async {
+        loop {
+            // A new client, so that no connection is reused
+            let other_client = https_client(address, &other_ca, None);
+            if https_get_todos(&other_client, address).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(10), reloaded)
+        .await
+        .expect("certificate was not reloaded");
+
+    let client = https_client(address, &ca, None);
+    assert!(https_get_todos(&client, address).await.is_err());
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_mutual_tls(pool: Pool<Sqlite>) {
+    let dir = tempfile::tempdir().unwrap();
+    let ca = CertificateAuthority::new();
+    let client_ca = CertificateAuthority::new();
+    let (address, _) = spawn_tls_server(pool, &dir, &ca, Some(&client_ca)).await;
+
+    // Without a client certificate
+    let client = https_client(address, &ca, None);
+    assert!(https_get_todos(&client, address).await.is_err());
+
+    // With a client certificate from another CA
+    let (cert, key) = ca.issue(rcgen::ExtendedKeyUsagePurpose::ClientAuth);
+    let identity = reqwest::Identity::from_pem(format!("{cert}{key}").as_bytes()).unwrap();
+    let client = https_client(address, &ca, Some(identity));
+    assert!(https_get_todos(&client, address).await.is_err());
+
+    let (cert, key) = client_ca.issue(rcgen::ExtendedKeyUsagePurpose::ClientAuth);
+    let identity = reqwest::Identity::from_pem(format!("{cert}{key}").as_bytes()).unwrap();
+    let client = https_client(address, &ca, Some(identity));
+    let todos = https_get_todos(&client, address).await.unwrap();
+    assert_eq!(todos.as_array().unwrap().len(), 3);
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_tls_handshake_timeout(pool: Pool<Sqlite>) {
+    use tokio::io::AsyncReadExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let ca = CertificateAuthority::new();
+    let (address, _) = spawn_tls_server(pool, &dir, &ca, None).await;
+
+    // A client that never starts the handshake is disconnected
+    let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+    let mut buf = [0; 1];
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+        .await
+        .expect("connection was not closed");
+    assert_eq!(read.unwrap(), 0);
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_unix_socket(pool: Pool<Sqlite>) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("todos.sock");
+    let config = ListenerConfig::Unix {
+        path: path.clone(),
+        mode: Some(0o600),
+    };
+
+    // A socket left behind by an earlier run is replaced
+    drop(Listener::bind(&config).await.unwrap());
+    assert!(path.exists());
+    let listener = Listener::bind(&config).await.unwrap();
+
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let app = app::router(app_state(&pool));
+    tokio::spawn(server::serve(listener, app, None));
+
+    let stream = UnixStream::connect(&path).await.unwrap();
+    let (mut sender, connection) = handshake::<_, Body>(TokioIo::new(stream)).await.unwrap();
+    tokio::spawn(connection);
+
+    let response = sender
+        .send_request(
+            Request::builder()
+                .uri("/todos")
+                .header(header::HOST, "localhost")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let todos: Vec<Value> = serde_json::from_slice(&body).unwrap();
+    assert!(!todos.is_empty());
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_client(pool: Pool<Sqlite>) {
+    let address = spawn_server(pool).await;
+    let client = TodoClient::new(&format!("http://{address}"), Some("token".into())).unwrap();
+
+    let todos = client.get_todos().await.unwrap();
+    assert!(!todos.is_empty());
+
+    let added = client
+        .add_todo(&endpoints::TodoAdd {
+            description: "From the command line".to_string(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(added.description, "From the command line");
+    assert!(!added.done);
+
+    let updated = client
+        .update_todo(
+            added.id,
+            &endpoints::TodoUpdate {
+                description: added.description,
+                done: true,
+            },
+        )
+        .await
+        .unwrap();
+    assert!(updated.done);
+    assert!(client.get_todo(added.id).await.unwrap().done);
+    assert_eq!(client.get_todos().await.unwrap().len(), todos.len() + 1);
+
+    let err = client.get_todo(1_000_000).await.err().unwrap().to_string();
+    assert!(
+        err.starts_with("not found (404 Not Found, request "),
+        "{err}"
+    );
+}
+
+#[sqlx::test]
+async fn test_migration_status(pool: Pool<Sqlite>) {
+    let statuses = admin::migration_status(&pool).await.unwrap();
+    assert_eq!(statuses.len(), admin::MIGRATOR.iter().count());
+    assert!(statuses
+        .iter()
+        .all(|status| status.state == MigrationState::Applied));
+
+    assert!(admin::migrate(&pool, true).await.unwrap().is_empty());
+
+    sqlx::query("DELETE FROM _sqlx_migrations WHERE version = 3")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let pending = admin::migrate(&pool, true).await.unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].version, 3);
+    assert_eq!(pending[0].state, MigrationState::Pending);
+}
+
+#[sqlx::test]
+async fn test_seed(pool: Pool<Sqlite>) {
+    let provider = SqliteTodoProvider::from(&pool);
+    let first = admin::seed(&provider, 20)
+        .await
+        .unwrap_or_else(|ProviderError(err)| panic!("{err}"));
+    let second = admin::seed(&provider, 20)
+        .await
+        .unwrap_or_else(|ProviderError(err)| panic!("{err}"));
+
+    assert_eq!(first.len(), 20);
+    let describe = |todos: &[endpoints::Todo]| -> Vec<(String, bool)> {
+        todos
+            .iter()
+            .map(|todo| (todo.description.clone(), todo.done))
+            .collect()
+    };
+    assert_eq!(describe(&first), describe(&second));
+    assert!(first.iter().any(|todo| todo.done));
+    assert!(first.iter().any(|todo| !todo.done));
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_backup_and_restore(pool: Pool<Sqlite>) {
+    let dir = tempfile::tempdir().unwrap();
+    let backup = dir.path().join("backup.db");
+    admin::backup(&pool, &backup).await.unwrap();
+    assert!(admin::backup(&pool, &backup).await.is_err());
+
+    let database = dir.path().join("todos.db");
+    std::fs::write(&database, "replaced").unwrap();
+    admin::restore(&backup, &database).await.unwrap();
+
+    let restored = open(&database).await;
+    assert_eq!(todos_in(&restored).await, todos_in(&pool).await);
+    restored.close().await;
+
+    // The database cannot be replaced while a server is using it
+    let options = SqliteConnectOptions::new()
+        .filename(&database)
+        .journal_mode(SqliteJournalMode::Wal);
+    let mut in_use = SqliteConnection::connect_with(&options).await.unwrap();
+    let count = "SELECT COUNT(*) FROM todos";
+    let (before,): (i64,) = sqlx::query_as(count).fetch_one(&mut in_use).await.unwrap();
+    let err = admin::restore(&backup, &database).await.unwrap_err();
+    assert!(err.to_string().contains("may be in use"), "{err}");
+    assert!(!dir.path().join("todos.db.restoring").exists());
+    let (after,): (i64,) = sqlx::query_as(count).fetch_one(&mut in_use).await.unwrap();
+    assert_eq!(after, before);
+    in_use.close().await.unwrap();
+    admin::restore(&backup, &database).await.unwrap();
+    assert!(!dir.path().join("todos.db-wal").exists());
+    assert_eq!(
+        todos_in(&open(&database).await).await,
+        todos_in(&pool).await
+    );
+
+    // Files that are not databases, or not todo databases, are not restored
+    let not_database = dir.path().join("not.db");
+    std::fs::write(&not_database, "not a database").unwrap();
+    assert!(admin::restore(&not_database, &database).await.is_err());
+
+    let other = dir.path().join("other.db");
+    let other_pool = SqlitePool::connect(&format!("sqlite:{}?mode=rwc", other.display()))
+        .await
+        .unwrap();
+    sqlx::query("CREATE TABLE things (id INTEGER PRIMARY KEY)")
+        .execute(&other_pool)
+        .await
+        .unwrap();
+    other_pool.close().await;
+    let err = admin::restore(&other, &database).await.unwrap_err();
+    assert!(err.to_string().contains("not a todo database"), "{err}");
+}
+
+async fn todos_in(pool: &Pool<Sqlite>) -> Vec<(i64, String, bool)> {
+    SqliteTodoProvider::from(pool)
+        .get_todos()
+        .await
+        .unwrap_or_else(|ProviderError(err)| panic!("{err}"))
+        .into_iter()
+        .map(|todo| (todo.id, todo.description, todo.done))
+        .collect()
+}
+
+async fn open(path: &std::path::Path) -> Pool<Sqlite> {
+    SqlitePool::connect(&format!("sqlite:{}", path.display()))
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_backup_endpoint(pool: Pool<Sqlite>) {
+    let mut state = app_state(&pool);
+    state.config.admin_token = Some("secret".to_string());
+    let address = serve(state).await;
+
+    let response = reqwest::Client::new()
+        .post(format!("http://{address}/admin/backup"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("backup.db");
+    std::fs::write(&path, response.bytes().await.unwrap()).unwrap();
+
+    assert_eq!(todos_in(&open(&path).await).await, todos_in(&pool).await);
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_snapshots(pool: Pool<Sqlite>) {
+    let dir = tempfile::tempdir().unwrap();
+    let config = SnapshotConfig {
+        dir: dir.path().join("snapshots"),
+        interval: Duration::from_secs(60),
+        retain: 2,
+    };
+    let backups = SqliteBackupProvider::from(&pool);
+
+    let first = snapshots::take(&backups, &config).await.unwrap();
+    let second = snapshots::take(&backups, &config).await.unwrap();
+    let third = snapshots::take(&backups, &config).await.unwrap();
+
+    assert!(!first.exists());
+    assert!(second.exists());
+    assert_eq!(std::fs::read_dir(&config.dir).unwrap().count(), 2);
+
+    assert_eq!(todos_in(&open(&third).await).await, todos_in(&pool).await);
+}
+
+#[tokio::test]
+async fn test_pools() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("todos.db");
+    let url = format!("sqlite:{}", path.display());
+    let pools = SqlitePools::connect(&url, &DatabaseConfig::default())
+        .await
+        .unwrap();
+    admin::MIGRATOR.run(&pools.writer).await.unwrap();
+
+    for pool in [&pools.writer, &pools.reader] {
+        let (journal_mode,): (String,) = sqlx::query_as("PRAGMA journal_mode")
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+        let (synchronous, foreign_keys): (i64, bool) =
+            sqlx::query_as("SELECT * FROM pragma_synchronous, pragma_foreign_keys")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        assert_eq!(synchronous, 1);
+        assert!(foreign_keys);
+    }
+
+    // Concurrent writes queue for the writer rather than failing with SQLITE_BUSY
+    let provider = SqliteTodoProvider::from(&pools);
+    let writes = (0..20).map(|i| {
+        let provider = provider.clone();
+        tokio::spawn(async move {
+            provider
+                .add_todo(&format!("Todo {i}"), "test")
+                .await
+                .unwrap_or_else(|ProviderError(err)| panic!("{err}"))
+        })
+    });
+    for write in writes.collect::<Vec<_>>() {
+        write.await.unwrap();
+    }
+    assert_eq!(todos_in(&pools.reader).await.len(), 20);
+
+    // Snapshots are taken from the reader
+    let backup = dir.path().join("backup.db");
+    admin::backup(&pools.reader, &backup).await.unwrap();
+    assert_eq!(todos_in(&open(&backup).await).await.len(), 20);
+}
+
+#[sqlx::test(fixtures("todos"))]
+async fn test_provider_layers(pool: Pool<Sqlite>) {
+    let provider = ProviderBuilder::new()
+        .layer(CacheLayer::new(CacheConfig::default()))
+        .layer(ResilienceLayer::new(ResilienceConfig::default()))
+        .build(SqliteTodoProvider::from(&pool));
+    let address = serve(SqliteAppState {
+        provider: provider.clone(),
+        webhooks: SqliteWebhookProvider::from(&pool),
+        backups: SqliteBackupProvider::from(&pool),
+        changes: ChangeFeed::default(),
+        rate_limiter: RateLimiter::default(),
+        config: AppConfig::default(),
+    })
+    .await;
+    let client = TodoClient::new(&format!("http://{address}"), None).unwrap();
+
+    let todos = client.get_todos().await.unwrap();
+    assert_eq!(client.get_todos().await.unwrap().len(), todos.len());
+    assert_eq!(provider.stats(), CacheStats { hits: 1, misses: 1 });
+
+    // Writes through the stack clear the cache
+    client
+        .add_todo(&endpoints::TodoAdd {
+            description: "Through the layers".to_string(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(client.get_todos().await.unwrap().len(), todos.len() + 1);
+    assert_eq!(provider.stats(), CacheStats { hits: 1, misses: 2 });
+}